@@ -1,188 +1,40 @@
 use std::cell::RefCell;
-use std::io::{Cursor};
-use std::time::Duration;
-use image::{DynamicImage, EncodableLayout, ExtendedColorType, ImageEncoder, ImageFormat};
+use std::str::FromStr;
+use std::rc::Rc;
+use image::ImageFormat;
 
 use leptos_mview::mview;
 use wasm_bindgen::JsCast;
 use wasm_bindgen::prelude::{Closure};
-use web_sys::{Event, File, FileList, HtmlInputElement};
-use crate::{generate_sample_image, generate_unique_key, AppState, DisplayImage};
-use wasm_bindgen::prelude::*;
-
-use leptos::{component, create_rw_signal, create_signal, event_target_value, provide_context, use_context, view, Callable, Callback, For, IntoView, RwSignal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate};
+use web_sys::{ClipboardEvent, DragEvent, Event, File, FileList, HtmlInputElement};
+use web_image_converter::archive::{read_archive, ArchiveFormat};
+use web_image_converter::color::supported_color_types;
+use web_image_converter::metadata::{supports_exif, supports_icc};
+use web_image_converter::preset::Preset;
+use web_image_converter::recipe::Recipe;
+use web_image_converter::upload::{check_upload_size, decode_upload, UploadError};
+use web_image_converter::naming::{default_extension, split_relative_path, CollisionPolicy, NameOutcome, NameTemplate, TemplateError, Variable, DEFAULT_TEMPLATE};
+use crate::input::{dropped_files, pasted_images, relative_path, Upload};
+use crate::js::onLaunchFiles;
+use crate::preset_bar::PresetBar;
+use crate::recipe_editor::RecipeEditor;
+use crate::worker_pool::WorkerPool;
+use crate::{generate_unique_key, AppState, DisplayImage, RejectedFile};
+use web_image_converter::options::{parse_hex_color, to_hex_color, AlphaMode, BmpDepth, ChromaSubsampling, EncodeOptions, ExifMode, IccMode, PngCompression, PngFilter, PnmEncoding, PnmKind, SelectOption, TiffCompression, WebpMode};
+
+use leptos::{component, create_effect, ev, event_target_checked, IntoAttribute, on_cleanup, window_event_listener, create_rw_signal, create_signal, event_target_value, provide_context, use_context, view, Callable, Callback, For, IntoView, ReadSignal, RwSignal, Show, Signal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate, SignalWith};
 use wasm_bindgen_futures::spawn_local;
 
-pub fn convert_image(img: DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ()> {
-    let mut buffer = Vec::new();
-    let mut cursor = Cursor::new(&mut buffer);
-
-    match format {
-        ImageFormat::Png => {
-            let encoder = image::codecs::png::PngEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Jpeg => {
-            let encoder = image::codecs::jpeg::JpegEncoder::new(&mut cursor);
-            let img = img.to_rgb8();
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::Rgb8, // TODO handle removing alpha for jpeg
-            ).unwrap();
-        },
-        ImageFormat::Gif => {
-            let mut encoder = image::codecs::gif::GifEncoder::new(&mut cursor);
-            encoder.encode(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        // ImageFormat::WebP => {
-        //     let encoder = image::codecs::webp::WebPEncoder::new(&mut cursor);
-        //     encoder.write_image(
-        //         img.as_bytes(),
-        //         img.width(),
-        //         img.height(),
-        //         ExtendedColorType::from(img.color()),
-        //     ).unwrap();
-        // },
-        ImageFormat::Pnm => {
-            let encoder = image::codecs::pnm::PnmEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Tiff => {
-            let encoder = image::codecs::tiff::TiffEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Tga => {
-            let encoder = image::codecs::tga::TgaEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Bmp => {
-            let encoder = image::codecs::bmp::BmpEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Ico => {
-            let encoder = image::codecs::ico::IcoEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Hdr => {
-            let encoder = image::codecs::hdr::HdrEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::OpenExr => {
-            let encoder = image::codecs::openexr::OpenExrEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Farbfeld => {
-            let encoder = image::codecs::farbfeld::FarbfeldEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Avif => {
-            let encoder = image::codecs::avif::AvifEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        ImageFormat::Qoi => {
-            let encoder = image::codecs::qoi::QoiEncoder::new(&mut cursor);
-            encoder.write_image(
-                img.as_bytes(),
-                img.width(),
-                img.height(),
-                ExtendedColorType::from(img.color()),
-            ).unwrap();
-        },
-        _ => {}
-    };
-//
-    Ok(buffer)
-}
-
-
-
-
 #[component]
 pub fn App() -> impl IntoView {
     let app_state = AppState { input_files: Default::default(), queued_files: Default::default(),
-        output_files: Default::default()};
+        output_files: Default::default(), rejected_files: Default::default(), auto_orient: create_rw_signal(true), pool: Rc::new(WorkerPool::new()), scheduler: Default::default(), session: Default::default()};
 
     provide_context(app_state.clone());
-    let tester_resolver = spawn_local(async move {
-        loop {
-            let queued = app_state.queued_files;
-            let queued_items = app_state.queued_files.get_untracked();
-
-
-            while !queued_items.is_empty() {
-                queued.update(|files| {
-                    let output_items = app_state.output_files;
-                    let mut file = files.pop().unwrap();
-                    let out_type = file.out_filetype.clone().unwrap();
-                    let encoded = convert_image(file.image.clone(), out_type);
-
-                    // process the image now.
-                    file.result = encoded.expect("Failed to process a picture to PNG");
-
-                    output_items.clone().update(|output_item| output_item.push(file));
-                })
-
-            }
-            async_std::task::sleep(Duration::from_micros(1000)).await;
-        }
-    });
+    spawn_local(app_state.clone().step_queue());
+    spawn_local(app_state.clone().restore_session());
+    let saved_state = app_state.clone();
+    create_effect(move |_| saved_state.save_session());
 
     mview! {
         div class="flex w-screen h-screen bg-gray-100 justify-center items-center" {
@@ -210,9 +62,78 @@ pub fn App() -> impl IntoView {
 #[component]
 fn DownloadButton() -> impl IntoView {
     let state = use_context::<AppState>().expect("AppState not provided");
+    let (archive, set_archive) = create_signal(ArchiveFormat::default());
+    let (template, set_template) = create_signal(Ok(NameTemplate::default()));
+    let (policy, set_policy) = create_signal(CollisionPolicy::default());
+    let (keep_folders, set_keep_folders) = create_signal(true);
+    let (report, set_report) = create_signal(Vec::<String>::new());
+
+    let download = move |_| {
+        if let Ok(template) = template.get() {
+            set_report.set(match state.download_selected(archive.get(), &template, policy.get(), keep_folders.get()) {
+                Ok(renamed) => renamed,
+                Err(err) => vec![format!("Couldn't write the archive: {err}")],
+            });
+        }
+    };
 
-    mview! {
-        button on:click={ move |_| state.download_selected() } {"Download"}
+    view! {
+        <OptionSelect label="Archive" value=archive.get_untracked()
+            on_change=move |format: ArchiveFormat| set_archive.set(format) />
+        <NameTemplateSettings on_change=move |parsed| set_template.set(parsed) />
+        <OptionSelect label="Duplicate names" value=policy.get_untracked()
+            on_change=move |policy: CollisionPolicy| set_policy.set(policy) />
+        <label class="flex w-full justify-between px-2">
+            "Keep folders"
+            <input type="checkbox" checked=true on:change=move |ev| set_keep_folders.set(event_target_checked(&ev)) />
+        </label>
+        <NamePreview template=template policy=policy keep_folders=keep_folders />
+        <button on:click=download disabled=move || template.with(Result::is_err)>"Download"</button>
+        <For each=move || report.get() key=|line| line.clone() let:line>
+            <p class="px-2 text-yellow-300">{line}</p>
+        </For>
+    }
+}
+
+#[component]
+fn NameTemplateSettings(#[prop(into)] on_change: Callback<Result<NameTemplate, TemplateError>>) -> impl IntoView {
+    let hint = format!("Variables: {}", Variable::names().collect::<Vec<_>>().join(", "));
+
+    view! {
+        <label class="flex w-full justify-between px-2" title=hint>
+            "File names"
+            <input type="text" value=DEFAULT_TEMPLATE
+                on:input=move |ev| on_change.call(NameTemplate::parse(&event_target_value(&ev))) />
+        </label>
+    }
+}
+
+/// The names the selected outputs will get, or why the template is invalid.
+#[component]
+fn NamePreview(
+    template: ReadSignal<Result<NameTemplate, TemplateError>>,
+    policy: ReadSignal<CollisionPolicy>,
+    keep_folders: ReadSignal<bool>,
+) -> impl IntoView {
+    const SHOWN: usize = 3;
+    let state = use_context::<AppState>().expect("AppState not provided");
+
+    move || match template.get() {
+        Err(err) => view! { <p class="px-2 text-red-800">{err.to_string()}</p> }.into_view(),
+        Ok(template) => {
+            let selected = state.selected_outputs();
+            let outcomes = AppState::output_names(&selected, &template, policy.get(), keep_folders.get());
+            let names = outcomes.iter().take(SHOWN)
+                .map(|outcome| match outcome {
+                    NameOutcome::Unique(name) => view! { <p class="px-2 truncate">{name.clone()}</p> },
+                    outcome => view! { <p class="px-2 truncate text-yellow-300">{outcome.describe()}</p> },
+                })
+                .collect::<Vec<_>>();
+            let more = (selected.len() > SHOWN).then(|| view! { <p class="px-2">{format!("and {} more", selected.len() - SHOWN)}</p> });
+            let collisions = outcomes.iter().filter(|outcome| !matches!(outcome, NameOutcome::Unique(_))).count();
+            let collisions = (collisions > 0).then(|| view! { <p class="px-2 text-yellow-300">{format!("{collisions} duplicate names")}</p> });
+            view! { {names} {more} {collisions} }.into_view()
+        },
     }
 }
 
@@ -222,12 +143,46 @@ pub fn ConversionOptionsPanel() -> impl IntoView {
     let app_state = use_context::<AppState>().expect("AppState not provided");
 
     let (output_format, set_output_format) = create_signal(ImageFormat::Png); // png is first selected
+    let encode_options = create_rw_signal(EncodeOptions::default());
+    let recipe = create_rw_signal(Recipe::default());
+    let extension = create_rw_signal(None);
+    let preset_name = create_rw_signal(String::new());
+    let loaded = create_rw_signal(0); // bumped by presets, the settings then start over from what they hold
+
+    // a choice made for another format doesn't carry over
+    let chosen_extension = move || {
+        let format = output_format.get();
+        extension.get()
+            .filter(|ext| format.extensions_str().contains(ext))
+            .unwrap_or_else(|| default_extension(format, &encode_options.get()))
+    };
+
+    let load_preset = move |preset: Preset| {
+        preset_name.set(preset.name);
+        set_output_format.set(preset.format);
+        encode_options.set(preset.options);
+        recipe.set(preset.recipe);
+        loaded.update(|loaded| *loaded += 1);
+    };
+
+    let settings = move || {
+        loaded.track();
+        view! {
+            <FormatSelector value=output_format.get_untracked() on_change=move |format| set_output_format.set(format) />
+            <EncoderSettings format=output_format options=encode_options />
+            <RecipeEditor recipe />
+            <AlphaSettings format=output_format options=encode_options />
+            <MetadataSettings format=output_format options=encode_options />
+        }
+    };
 
     mview! {
         div class="flex items-center justify-center h-full"{
             div class="flex flex-col items-center justify-center h-5/6 w-full bg-primary h-full text-sm" {
-                FormatSelector on_change={move |format| set_output_format.set(format)};
-                button class="px-4 py-2 bg-button w-full lg:h-24 lg:w-1/4 bg-button text-sm" on:click={move |_| app_state.queue_selected(output_format.get())} {
+                PresetBar name={preset_name} format={output_format} options={encode_options} recipe={recipe} on_load={load_preset};
+                {settings}
+                ExtensionSelect format={output_format} value={Signal::derive(chosen_extension)} on_change={move |ext| extension.set(Some(ext))};
+                button class="px-4 py-2 bg-button w-full lg:h-24 lg:w-1/4 bg-button text-sm" on:click={move |_| app_state.queue_selected(output_format.get(), chosen_extension(), encode_options.get(), recipe.get())} {
                     "Convert"
                 }
             }
@@ -235,43 +190,217 @@ pub fn ConversionOptionsPanel() -> impl IntoView {
     }
 }
 
+/// Lets the user pick between a format's alternate extensions, like `jpg` and `jpeg`.
+#[component]
+fn ExtensionSelect(
+    format: ReadSignal<ImageFormat>,
+    value: Signal<&'static str>,
+    #[prop(into)] on_change: Callback<&'static str>,
+) -> impl IntoView {
+    let update = move |ev| {
+        let picked = event_target_value(&ev);
+        if let Some(ext) = format.get_untracked().extensions_str().iter().find(|ext| **ext == picked) {
+            on_change.call(ext);
+        }
+    };
+
+    view! {
+        <Show when=move || { format.get().extensions_str().len() > 1 }>
+            <label class="flex w-full justify-between px-2">
+                "Extension"
+                <select on:change=update>
+                    {move || format.get().extensions_str().iter().map(|ext| view! {
+                        <option value=*ext selected=move || value.get() == *ext>{*ext}</option>
+                    }).collect::<Vec<_>>()}
+                </select>
+            </label>
+        </Show>
+    }
+}
+
+#[component]
+fn EncoderSettings(format: ReadSignal<ImageFormat>, options: RwSignal<EncodeOptions>) -> impl IntoView {
+    let initial = options.get_untracked();
+
+    move || match format.get() {
+        ImageFormat::Jpeg => view! {
+            <NumberSetting label="Quality" min=1 max=100 value=initial.jpeg.quality
+                on_change=move |quality| options.update(|o| o.jpeg.quality = quality) />
+            <OptionSelect label="Chroma subsampling" value=initial.jpeg.subsampling
+                on_change=move |subsampling: ChromaSubsampling| options.update(|o| o.jpeg.subsampling = subsampling) />
+        }.into_view(),
+        ImageFormat::Png => view! {
+            <OptionSelect label="Compression" value=initial.png.compression
+                on_change=move |compression: PngCompression| options.update(|o| o.png.compression = compression) />
+            <OptionSelect label="Filter" value=initial.png.filter
+                on_change=move |filter: PngFilter| options.update(|o| o.png.filter = filter) />
+        }.into_view(),
+        ImageFormat::Avif => view! {
+            <NumberSetting label="Speed" min=1 max=10 value=initial.avif.speed
+                on_change=move |speed| options.update(|o| o.avif.speed = speed) />
+            <NumberSetting label="Quality" min=1 max=100 value=initial.avif.quality
+                on_change=move |quality| options.update(|o| o.avif.quality = quality) />
+        }.into_view(),
+        ImageFormat::Tiff => view! {
+            <OptionSelect label="Compression" value=initial.tiff.compression
+                on_change=move |compression: TiffCompression| options.update(|o| o.tiff.compression = compression) />
+        }.into_view(),
+        ImageFormat::Pnm => view! {
+            <OptionSelect label="Subtype" value=initial.pnm.subtype
+                on_change=move |subtype: PnmKind| options.update(|o| o.pnm.subtype = subtype) />
+            <OptionSelect label="Encoding" value=initial.pnm.encoding
+                on_change=move |encoding: PnmEncoding| options.update(|o| o.pnm.encoding = encoding) />
+        }.into_view(),
+        ImageFormat::WebP => view! {
+            <OptionSelect label="Mode" value=initial.webp.mode
+                on_change=move |mode: WebpMode| options.update(|o| o.webp.mode = mode) />
+            <NumberSetting label="Quality" min=1 max=100 value=initial.webp.quality
+                on_change=move |quality| options.update(|o| o.webp.quality = quality) />
+        }.into_view(),
+        ImageFormat::Bmp => view! {
+            <OptionSelect label="Bit depth" value=initial.bmp.depth
+                on_change=move |depth: BmpDepth| options.update(|o| o.bmp.depth = depth) />
+        }.into_view(),
+        _ => ().into_view(),
+    }
+}
+
+/// Shown only for targets without an alpha channel.
+#[component]
+fn AlphaSettings(format: ReadSignal<ImageFormat>, options: RwSignal<EncodeOptions>) -> impl IntoView {
+    let initial = options.get_untracked().alpha;
+    let (mode, set_mode) = create_signal(initial.mode);
+    let has_alpha = move || {
+        supported_color_types(format.get(), &options.get()).iter().any(|color| color.has_alpha())
+    };
+
+    let update_background = move |ev| {
+        if let Some(color) = parse_hex_color(&event_target_value(&ev)) {
+            options.update(|o| o.alpha.background = color);
+        }
+    };
+
+    view! {
+        <Show when=move || !has_alpha()>
+            <OptionSelect label="Transparency" value=initial.mode
+                on_change=move |new_mode: AlphaMode| {
+                    set_mode.set(new_mode);
+                    options.update(|o| o.alpha.mode = new_mode);
+                } />
+            <Show when=move || mode.get() == AlphaMode::Background>
+                <label class="flex w-full justify-between px-2">
+                    "Background"
+                    <input type="color" value=to_hex_color(options.get_untracked().alpha.background)
+                        on:input=update_background />
+                </label>
+            </Show>
+        </Show>
+    }
+}
+
+#[component]
+fn MetadataSettings(format: ReadSignal<ImageFormat>, options: RwSignal<EncodeOptions>) -> impl IntoView {
+    let initial = options.get_untracked().metadata;
+
+    view! {
+        <OptionSelect label="EXIF" value=initial.exif
+            on_change=move |exif: ExifMode| options.update(|o| o.metadata.exif = exif) />
+        <Show when=move || !supports_exif(format.get()) && options.get().metadata.exif != ExifMode::StripAll>
+            <p class="w-full px-2 text-yellow-300">
+                {move || if format.get() == ImageFormat::Tiff {
+                    "TIFF output is written without EXIF, it will be dropped"
+                } else {
+                    "This format can't hold EXIF, it will be dropped"
+                }}
+            </p>
+        </Show>
+        <OptionSelect label="Color profile" value=initial.icc
+            on_change=move |icc: IccMode| options.update(|o| o.metadata.icc = icc) />
+        <Show when=move || !supports_icc(format.get()) && options.get().metadata.icc == IccMode::Keep>
+            <p class="w-full px-2 text-yellow-300">"This format can't hold a color profile, convert to sRGB to keep colors right"</p>
+        </Show>
+    }
+}
+
+#[component]
+pub(crate) fn OptionSelect<T: SelectOption>(
+    label: &'static str,
+    value: T,
+    #[prop(into)] on_change: Callback<T>,
+) -> impl IntoView {
+    let update = move |ev| {
+        if let Some(option) = T::from_label(&event_target_value(&ev)) {
+            on_change.call(option);
+        }
+    };
+
+    view! {
+        <label class="flex w-full justify-between px-2">
+            {label}
+            <select on:change=update>
+                {T::ALL.iter().map(|option| view! {
+                    <option value=option.label() selected={*option == value}>{option.label()}</option>
+                }).collect::<Vec<_>>()}
+            </select>
+        </label>
+    }
+}
+
+#[component]
+pub(crate) fn NumberSetting<T: Copy + Ord + FromStr + IntoAttribute + 'static>(
+    label: &'static str,
+    min: T,
+    max: T,
+    value: T,
+    #[prop(into)] on_change: Callback<T>,
+) -> impl IntoView {
+    let update = move |ev| {
+        if let Ok(number) = event_target_value(&ev).parse::<T>() {
+            on_change.call(number.clamp(min, max));
+        }
+    };
+
+    view! {
+        <label class="flex w-full justify-between px-2">
+            {label}
+            <input type="number" class="w-16" min=min max=max value=value on:change=update />
+        </label>
+    }
+}
+
+
+const FORMATS: &[(&str, ImageFormat)] = &[
+    ("PNG", ImageFormat::Png),
+    ("AVIF", ImageFormat::Avif),
+    ("BMP", ImageFormat::Bmp),
+    ("GIF", ImageFormat::Gif),
+    ("HDR", ImageFormat::Hdr),
+    ("ICO", ImageFormat::Ico),
+    ("JPEG", ImageFormat::Jpeg),
+    ("EXR", ImageFormat::OpenExr),
+    ("PNM", ImageFormat::Pnm),
+    ("TGA", ImageFormat::Tga),
+    ("TIFF", ImageFormat::Tiff),
+    ("WEBP", ImageFormat::WebP),
+];
 
 #[component]
 fn FormatSelector(
+    value: ImageFormat,
     #[prop(into)] on_change: Callback<ImageFormat>
 ) -> impl IntoView {
     let update_format = move |ev| {
-        let format = event_target_value(&ev);
-        let image_format = match format.as_str() {
-            "PNG" => ImageFormat::Png,
-            "BMP" => ImageFormat::Bmp,
-            "GIF" => ImageFormat::Gif,
-            "HDR" => ImageFormat::Hdr,
-            "ICO" => ImageFormat::Ico,
-            "JPEG" => ImageFormat::Jpeg,
-            "EXR" => ImageFormat::OpenExr,
-            "PNM" => ImageFormat::Pnm,
-            "TGA" => ImageFormat::Tga,
-            "TIFF" => ImageFormat::Tiff,
-            "WEBP" => ImageFormat::WebP,
-            _ => ImageFormat::Png, // Default to PNG if unknown, TODO error handling later, should inform & ignore
-        };
-        on_change.call(image_format);
+        let picked = event_target_value(&ev);
+        if let Some((_, format)) = FORMATS.iter().find(|(label, _)| *label == picked) {
+            on_change.call(*format);
+        }
     };
 
     view! {
         <select class="w-full" id="format-selector" name="format" on:change=update_format>
-            <option value="PNG">"PNG"</option>
-            <option value="BMP">"BMP"</option>
-            <option value="GIF">"GIF"</option>
-            <option value="HDR">"HDR"</option>
-            <option value="ICO">"ICO"</option>
-            <option value="JPEG">"JPEG"</option>
-            <option value="EXR">"EXR"</option>
-            <option value="PNM">"PNM"</option>
-            <option value="TGA">"TGA"</option>
-            <option value="TIFF">"TIFF"</option>
-            <option value="WEBP">"WEBP"</option>
+            {FORMATS.iter().map(|(label, format)| view! {
+                <option value=*label selected={*format == value}>{*label}</option>
+            }).collect::<Vec<_>>()}
         </select>
     }
 }
@@ -280,26 +409,87 @@ fn FormatSelector(
 #[component]
 pub fn UploadedImagesContainer() -> impl IntoView {
     let app_state = use_context::<AppState>().expect("AppState not provided");
+    let (dragging, set_dragging) = create_signal(false);
+
+    let on_dragover = move |ev: DragEvent| {
+        ev.prevent_default(); // otherwise the browser won't let us take the drop
+        set_dragging.set(true);
+    };
+    let on_drop = move |ev: DragEvent| {
+        ev.prevent_default();
+        set_dragging.set(false);
+        if let Some(transfer) = ev.data_transfer() {
+            dropped_files(&transfer, |upload| process_files(vec![upload]));
+        }
+    };
+
+    let paste = window_event_listener(ev::paste, |ev| {
+        // leptos types `paste` as a plain Event
+        let Ok(ev) = ev.dyn_into::<ClipboardEvent>() else {
+            return;
+        };
+        if let Some(transfer) = ev.clipboard_data() {
+            let images = pasted_images(&transfer);
+            if !images.is_empty() {
+                ev.prevent_default();
+                process_files(images);
+            }
+        }
+    });
+    on_cleanup(move || paste.remove());
+
+    // "Open with" on an image in the OS, once the app is installed
+    let launched = Closure::<dyn Fn(File)>::new(|file: File| process_files(vec![(file.name(), file.into())]));
+    onLaunchFiles(launched.as_ref().unchecked_ref());
+    launched.forget(); // the launch queue keeps it for the life of the page
 
     mview! {
-        div class="h-full flex-col flex" {
+        div class="h-full flex-col flex"
+            class:outline-dashed={dragging}
+            on:dragover={on_dragover}
+            on:dragleave={move |_| set_dragging.set(false)}
+            on:drop={on_drop} {
             h1 class="lg:text-xl text-center" {"Uploaded"}
             div class="h-40 w-full" {
                 ImageUploader;
                 ImageContainer id="upload-images" source={app_state.input_files};
+                RejectedFiles;
             }
         }
 
     }
 }
 
+/// Uploads that couldn't be loaded, with the reason for each.
+#[component]
+fn RejectedFiles() -> impl IntoView {
+    let rejected = use_context::<AppState>().expect("AppState not provided").rejected_files;
+
+    view! {
+        <Show when=move || rejected.with(|rejected| !rejected.is_empty())>
+            <div class="w-full bg-primary border-2 text-sm">
+                <div class="flex flex-row">
+                    <h2 class="grow px-2 text-red-800">"Rejected"</h2>
+                    <button class="px-4 bg-button" on:click=move |_| rejected.set(Vec::new())>"Clear"</button>
+                </div>
+                <For each=move || rejected.get() key=|file| file.id.clone() let:file>
+                    <p class="px-2">{format!("{}: {}", file.name, file.reason)}</p>
+                </For>
+            </div>
+        </Show>
+    }
+}
+
 #[component]
 pub fn QueuedImagesContainer() -> impl IntoView {
     let app_state = use_context::<AppState>().expect("AppState not provided");
 
     mview! {
         div class="h-full flex flex-col justify-center" {
-            h1 class="lg:text-xl text-center bg-secondary" {"Queued"}
+            div class="flex flex-row justify-center bg-secondary" {
+                h1 class="lg:text-xl text-center grow" {"Queued"}
+                QueueControls;
+            }
             div class="h-40 w-full" {
                 ImageContainer id="upload-images" source={app_state.queued_files};
             }
@@ -309,6 +499,28 @@ pub fn QueuedImagesContainer() -> impl IntoView {
     }
 }
 
+#[component]
+fn QueueControls() -> impl IntoView {
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+    let paused = app_state.scheduler.paused;
+    let cancel_state = app_state.clone();
+
+    let toggle = move |_| {
+        if paused.get_untracked() {
+            app_state.scheduler.resume();
+        } else {
+            app_state.scheduler.pause();
+        }
+    };
+
+    mview! {
+        button class="px-4 bg-button text-sm" on:click={toggle} {
+            {move || if paused.get() { "Resume" } else { "Pause" }}
+        }
+        button class="px-4 ml-1 bg-button text-sm" on:click={move |_| cancel_state.cancel_all()} {"Cancel all"}
+    }
+}
+
 #[component]
 pub fn OutputImagesContainer() -> impl IntoView {
     let state = use_context::<AppState>().expect("AppState not provided");
@@ -327,16 +539,12 @@ pub fn OutputImagesContainer() -> impl IntoView {
 
 #[component]
 pub fn ImageUploader() -> impl IntoView {
-    let state = use_context::<AppState>().expect("AppState not provided");
-
-    let (file_count, set_file_count) = create_signal(0);
-
-    let app_clone = state.clone();
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+    let clear_state = app_state.clone();
     let on_files_change = move |ev: Event| {
-        let app_state = state.clone();
         let input: HtmlInputElement = ev.target().unwrap().unchecked_into();
         if let Some(file_list) = input.files() {
-            process_files(file_list);
+            process_files(file_list_uploads(&file_list));
         }
     };
 
@@ -344,21 +552,33 @@ pub fn ImageUploader() -> impl IntoView {
         <div class="flex">
             <label class="inline-flex grow items-center px-4 py-2 \
                 bg-button rounded-md shadow-sm cursor-pointer hover:bg-gray-50">
-              <input type="file" accept="image/*" class="hidden" on:change=on_files_change multiple />
+              <input type="file" accept="image/*,.tar,.gz,.tgz,.zip" class="hidden" on:change=on_files_change multiple />
               <span class="text-sm w-full text-center">Choose Files</span>
             </label>
+            <label class="inline-flex grow items-center px-4 py-2 \
+                bg-button rounded-md shadow-sm cursor-pointer hover:bg-gray-50">
+              <input type="file" class="hidden" on:change=on_files_change webkitdirectory multiple />
+              <span class="text-sm w-full text-center">Choose Folder</span>
+            </label>
+            <label class="inline-flex items-center gap-2 px-4 text-sm" title="Rotate photos the way the camera recorded them">
+              <input type="checkbox" checked=app_state.auto_orient.get_untracked()
+                  on:change=move |ev| app_state.auto_orient.set(event_target_checked(&ev)) />
+              "Auto-rotate"
+            </label>
+            <button class="px-4 text-sm" title="Remove every image, here and from the browser's storage"
+                on:click=move |_| clear_state.clear_session()>
+              "Clear session"
+            </button>
         </div>
     }
 }
 #[component]
 pub fn ImageContainer(id: &'static str, source: RwSignal<Vec<DisplayImage>>) -> impl IntoView {
-    let state = use_context::<AppState>().expect("AppState not provided");
-
     let (all_selected, set_all_selected) = create_signal(false);
-    let select_state = state.clone();
 
-    let select_all_toggle = move |mc| {
+    let select_all_toggle = move |_| {
         let select_state = !all_selected.get();
+        set_all_selected.set(select_state);
         source.update(|files| {
             files.iter_mut().for_each(|img| {
                 img.is_selected.set(select_state);
@@ -391,18 +611,32 @@ pub fn add_image(new_image: DisplayImage) {
     app_state.input_files.update(|images| images.push(new_image));
 }
 
-fn process_files(file_list: FileList) {
-    let files: Vec<File> = (0..file_list.length())
+pub fn reject_file(name: String, reason: UploadError) {
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+    app_state.rejected_files.update(|rejected| rejected.push(RejectedFile { id: generate_unique_key(), name, reason }));
+}
+
+fn file_list_uploads(file_list: &FileList) -> Vec<Upload> {
+    (0..file_list.length())
         .filter_map(|i| file_list.get(i))
-        .collect();
+        .map(|file| (relative_path(&file), file.into()))
+        .collect()
+}
+
+fn process_files(files: Vec<Upload>) {
     let reusable_buffer = std::rc::Rc::new(RefCell::new(Vec::with_capacity(8192)));
 
-    for file in files {
+    for (path, file) in files {
+        // don't read what would be rejected anyway
+        if let Err(reason) = check_upload_size(file.size() as u64) {
+            reject_file(path, reason);
+            continue;
+        }
+
         let file_reader = web_sys::FileReader::new().unwrap();
         let file_reader = std::rc::Rc::new(file_reader);
         let file_reader_clone = file_reader.clone();
 
-        let file_name = file.name();
         let buffer_clone = reusable_buffer.clone();
 
         let onload = Closure::wrap(Box::new(move |_: Event| {
@@ -410,27 +644,7 @@ fn process_files(file_list: FileList) {
                 let uint8_array = js_sys::Uint8Array::new(&buffer);
 
                 let vec = uint8_array.to_vec();
-                let format = image::guess_format(&vec).ok().expect("Invalid file format!");
-
-                // Create DynamicImage from memory
-                if let Ok(img) = image::load_from_memory(&vec) {
-                    let mut buffer = buffer_clone.borrow_mut();
-                    buffer.clear();  // Clear the buffer before reuse
-                    add_image(DisplayImage {
-                            id: generate_unique_key(),
-                            is_completed: false,
-                            is_selected: create_rw_signal(false),
-                            name: file_name.clone(),
-                            in_filetype: format.extensions_str()[0],
-                            out_filetype: None,
-                            time_completed: None,
-                            preview: generate_sample_image(&img, &mut buffer),
-                            image: img,
-                        result: vec![],
-                        in_file: Default::default(),
-                        out_file: None,
-                    });
-                }
+                load_upload(&path, vec, &mut buffer_clone.borrow_mut());
             }
         }) as Box<dyn FnMut(_)>);
 
@@ -439,3 +653,34 @@ fn process_files(file_list: FileList) {
         onload.forget();
     }
 }
+
+/// Adds an upload to the Uploaded list, or every image in it if it's an archive.
+fn load_upload(path: &str, bytes: Vec<u8>, buffer: &mut Vec<u8>) {
+    match read_archive(&bytes) {
+        None => load_image(path, bytes, buffer),
+        Some(Err(err)) => reject_file(path.to_string(), UploadError::Archive(err.to_string())),
+        Some(Ok(members)) => {
+            // members keep their path inside the archive, below the folder the archive came from
+            let (folder, _) = split_relative_path(path);
+            for member in members {
+                let member_path = if folder.is_empty() { member.path } else { format!("{folder}/{}", member.path) };
+                load_image(&member_path, member.data, buffer);
+            }
+        },
+    }
+}
+
+fn load_image(path: &str, bytes: Vec<u8>, buffer: &mut Vec<u8>) {
+    let (folder, file_name) = split_relative_path(path);
+
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+
+    // Create DynamicImage from memory
+    let auto_orient = app_state.auto_orient.get_untracked();
+    let (in_file, img) = match decode_upload(file_name.to_string(), bytes, auto_orient) {
+        Ok(decoded) => decoded,
+        Err(reason) => return reject_file(path.to_string(), reason),
+    };
+
+    add_image(DisplayImage::new(file_name.to_string(), folder.to_string(), in_file, img, auto_orient, buffer));
+}
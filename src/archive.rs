@@ -0,0 +1,257 @@
+//! Bundles the converted images into the single file that gets downloaded,
+//! and unpacks archives that are uploaded.
+
+use std::io::{Cursor, Read, Write};
+use std::path::{Component, Path};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use tar::{Builder, Header};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::options::SelectOption;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    ZipStored,
+    #[default]
+    ZipDeflate,
+}
+
+impl SelectOption for ArchiveFormat {
+    const ALL: &'static [Self] = &[Self::Tar, Self::TarGz, Self::ZipStored, Self::ZipDeflate];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Tar => "TAR",
+            Self::TarGz => "TAR (gzip)",
+            Self::ZipStored => "ZIP (stored)",
+            Self::ZipDeflate => "ZIP (deflate)",
+        }
+    }
+}
+
+impl ArchiveFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Self::Tar => "output.tar",
+            Self::TarGz => "output.tar.gz",
+            Self::ZipStored | Self::ZipDeflate => "output.zip",
+        }
+    }
+}
+
+/// A file to put in the archive.
+pub struct ArchiveEntry<'a> {
+    pub path: String,
+    pub data: &'a [u8],
+}
+
+pub fn write_archive(format: ArchiveFormat, entries: &[ArchiveEntry]) -> std::io::Result<Vec<u8>> {
+    match format {
+        ArchiveFormat::Tar => write_tar(Vec::new(), entries),
+        ArchiveFormat::TarGz => write_tar(GzEncoder::new(Vec::new(), Compression::default()), entries)?.finish(),
+        ArchiveFormat::ZipStored => write_zip(CompressionMethod::Stored, entries),
+        ArchiveFormat::ZipDeflate => write_zip(CompressionMethod::Deflated, entries),
+    }
+}
+
+fn write_tar<W: Write>(writer: W, entries: &[ArchiveEntry]) -> std::io::Result<W> {
+    let mut tar = Builder::new(writer);
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_size(entry.data.len() as u64);
+        header.set_mode(0o644);
+        // writes a GNU long name record first when the path doesn't fit the header
+        tar.append_data(&mut header, &entry.path, entry.data)?;
+    }
+    tar.into_inner()
+}
+
+fn write_zip(method: CompressionMethod, entries: &[ArchiveEntry]) -> std::io::Result<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default()
+        .compression_method(method)
+        .unix_permissions(0o644);
+
+    for entry in entries {
+        zip.start_file(entry.path.as_str(), options)?;
+        zip.write_all(entry.data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+}
+
+/// A file taken out of an uploaded archive.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveMember {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+/// Unpacks a tar, tar.gz or zip upload, telling them apart by their
+/// contents. Returns `None` for anything that isn't one of those.
+pub fn read_archive(bytes: &[u8]) -> Option<std::io::Result<Vec<ArchiveMember>>> {
+    if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+        Some(read_zip(bytes))
+    } else if bytes.starts_with(&[0x1f, 0x8b]) {
+        Some(read_tar(GzDecoder::new(bytes)))
+    } else if is_tar(bytes) {
+        Some(read_tar(bytes))
+    } else {
+        None
+    }
+}
+
+// every tar header since POSIX.1-1988 has the magic at this offset
+fn is_tar(bytes: &[u8]) -> bool {
+    bytes.get(257..262) == Some(b"ustar")
+}
+
+fn read_tar(reader: impl Read) -> std::io::Result<Vec<ArchiveMember>> {
+    let mut members = Vec::new();
+    for entry in tar::Archive::new(reader).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let Some(path) = clean_path(&entry.path()?) else {
+            continue;
+        };
+        let mut data = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut data)?;
+        members.push(ArchiveMember { path, data });
+    }
+    Ok(members)
+}
+
+fn read_zip(bytes: &[u8]) -> std::io::Result<Vec<ArchiveMember>> {
+    let mut zip = ZipArchive::new(Cursor::new(bytes))?;
+    let mut members = Vec::new();
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i)?;
+        if !file.is_file() {
+            continue;
+        }
+
+        let Some(path) = file.enclosed_name().and_then(|path| clean_path(&path)) else {
+            continue;
+        };
+        let mut data = Vec::with_capacity(file.size() as usize);
+        file.read_to_end(&mut data)?;
+        members.push(ArchiveMember { path, data });
+    }
+    Ok(members)
+}
+
+// member paths are only used as names, but keep them relative and free of `..` all the same
+fn clean_path(path: &Path) -> Option<String> {
+    let parts: Vec<_> = path.components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect();
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<ArchiveEntry<'static>> {
+        vec![
+            ArchiveEntry { path: "a.png".to_string(), data: b"first image" },
+            ArchiveEntry { path: "b.webp".to_string(), data: &[0; 4096] },
+        ]
+    }
+
+    fn tar_contents(reader: impl Read) -> Vec<(String, Vec<u8>)> {
+        tar::Archive::new(reader).entries().unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let path = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (path, data)
+            })
+            .collect()
+    }
+
+    fn expected() -> Vec<(String, Vec<u8>)> {
+        entries().into_iter().map(|entry| (entry.path, entry.data.to_vec())).collect()
+    }
+
+    #[test]
+    fn tar_round_trip() {
+        let tar = write_archive(ArchiveFormat::Tar, &entries()).unwrap();
+        assert_eq!(tar_contents(tar.as_slice()), expected());
+
+        let tar_gz = write_archive(ArchiveFormat::TarGz, &entries()).unwrap();
+        assert_eq!(tar_contents(GzDecoder::new(tar_gz.as_slice())), expected());
+    }
+
+    #[test]
+    fn zip_round_trip() {
+        for format in [ArchiveFormat::ZipStored, ArchiveFormat::ZipDeflate] {
+            let zip = write_archive(format, &entries()).unwrap();
+            let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+
+            let contents: Vec<_> = (0..archive.len())
+                .map(|i| {
+                    let mut file = archive.by_index(i).unwrap();
+                    let mut data = Vec::new();
+                    file.read_to_end(&mut data).unwrap();
+                    (file.name().to_string(), data)
+                })
+                .collect();
+            assert_eq!(contents, expected());
+        }
+    }
+
+    #[test]
+    fn reads_back_written_archives() {
+        let members: Vec<_> = expected().into_iter().map(|(path, data)| ArchiveMember { path, data }).collect();
+        for format in ArchiveFormat::ALL {
+            let archive = write_archive(*format, &entries()).unwrap();
+            assert_eq!(read_archive(&archive).unwrap().unwrap(), members, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn long_paths_survive_every_format() {
+        let path = format!("{}/{}.png", ["photos", "2024", "holiday in the mountains"].repeat(4).join("/"), "x".repeat(64));
+        assert!(path.len() > 100);
+
+        for format in ArchiveFormat::ALL {
+            let archive = write_archive(*format, &[ArchiveEntry { path: path.clone(), data: b"long" }]).unwrap();
+            let members = read_archive(&archive).unwrap().unwrap();
+            assert_eq!(members, [ArchiveMember { path: path.clone(), data: b"long".to_vec() }], "{format:?}");
+        }
+    }
+
+    #[test]
+    fn member_paths_stay_relative() {
+        let mut tar = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.as_gnu_mut().unwrap().name[..15].copy_from_slice(b"/../dir/./a.png");
+        header.set_cksum();
+        tar.append(&header, b"x".as_slice()).unwrap();
+
+        let members = read_archive(&tar.into_inner().unwrap()).unwrap().unwrap();
+        assert_eq!(members[0].path, "dir/a.png");
+    }
+
+    #[test]
+    fn other_files_are_not_archives() {
+        assert!(read_archive(b"\x89PNG\r\n\x1a\n").is_none());
+        assert!(read_archive(b"PK\x03\x04 truncated").unwrap().is_err());
+    }
+}
@@ -0,0 +1,36 @@
+// Conversion worker, built by trunk as `worker.js` next to the main bundle and
+// started by the `worker_loader.js` shim it writes alongside.
+// It receives serialized `ConversionJob`s and answers with `WorkerReply`s.
+
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{DedicatedWorkerGlobalScope, MessageEvent};
+use web_image_converter::protocol::handle_message;
+
+fn main() {
+    console_error_panic_hook::set_once();
+
+    let scope: DedicatedWorkerGlobalScope = js_sys::global().unchecked_into();
+    let reply_scope = scope.clone();
+
+    let onmessage = Closure::wrap(Box::new(move |msg: MessageEvent| {
+        let bytes = Uint8Array::new(&msg.data()).to_vec();
+        let reply = |response: Vec<u8>| {
+            let response = Uint8Array::from(response.as_slice());
+            let transfer = Array::of1(&response.buffer());
+            reply_scope.post_message_with_transfer(&response, &transfer)
+                .expect("Failed to post conversion reply");
+        };
+
+        if let Err(err) = handle_message(&bytes, reply) {
+            web_sys::console::error_1(&format!("Malformed conversion job: {err}").into());
+        }
+    }) as Box<dyn FnMut(_)>);
+
+    scope.set_onmessage(Some(onmessage.as_ref().unchecked_ref()));
+    onmessage.forget();
+
+    // messages posted before the wasm module finished loading are lost, so tell the page we're ready
+    scope.post_message(&JsValue::from(Array::new())).expect("Failed to post ready message");
+}
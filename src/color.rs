@@ -0,0 +1,234 @@
+use image::{ColorType, DynamicImage, GenericImageView, ImageFormat, Rgb32FImage};
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, TransformExecutor, TransformOptions};
+
+use crate::error::ConversionError;
+use crate::options::{AlphaMode, BmpDepth, EncodeOptions, PnmKind};
+
+const GRAY_COLOR_8: &[ColorType] = &[ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8];
+const RGB_8: &[ColorType] = &[ColorType::Rgb8, ColorType::Rgba8];
+
+/// The color types the encoder for `format` accepts with the given options.
+pub fn supported_color_types(format: ImageFormat, options: &EncodeOptions) -> &'static [ColorType] {
+    match format {
+        ImageFormat::Png => &[
+            ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8,
+            ColorType::L16, ColorType::La16, ColorType::Rgb16, ColorType::Rgba16,
+        ],
+        ImageFormat::Jpeg => &[ColorType::L8, ColorType::Rgb8],
+        ImageFormat::Pnm => match options.pnm.subtype {
+            PnmKind::Bitmap | PnmKind::Graymap => &[ColorType::L8],
+            PnmKind::Pixmap => &[ColorType::Rgb8],
+            PnmKind::ArbitraryMap => &[
+                ColorType::L8, ColorType::La8, ColorType::Rgb8, ColorType::Rgba8,
+                ColorType::L16, ColorType::La16, ColorType::Rgb16, ColorType::Rgba16,
+            ],
+        },
+        ImageFormat::Tiff => &[
+            ColorType::L8, ColorType::Rgb8, ColorType::Rgba8,
+            ColorType::L16, ColorType::Rgb16, ColorType::Rgba16,
+            ColorType::Rgb32F, ColorType::Rgba32F,
+        ],
+        ImageFormat::Bmp => match options.bmp.depth {
+            BmpDepth::Bits24 => &[ColorType::L8, ColorType::Rgb8],
+            BmpDepth::Bits32 => GRAY_COLOR_8,
+        },
+        ImageFormat::WebP | ImageFormat::Tga | ImageFormat::Ico => GRAY_COLOR_8,
+        ImageFormat::Gif | ImageFormat::Avif | ImageFormat::Qoi => RGB_8,
+        ImageFormat::Hdr => &[ColorType::Rgb32F],
+        ImageFormat::OpenExr => &[ColorType::Rgb32F, ColorType::Rgba32F],
+        ImageFormat::Farbfeld => &[ColorType::Rgba16],
+        _ => &[],
+    }
+}
+
+/// Picks the supported color type that loses the least of `source`. Dropping
+/// alpha or color is worse than dropping precision, and widening is only a
+/// cost in file size.
+pub fn closest_color_type(source: ColorType, supported: &[ColorType]) -> Option<ColorType> {
+    supported.iter().copied().min_by_key(|&target| {
+        let loss = ColorLoss::between(source, target);
+        let mut cost = 0;
+        if loss.alpha {
+            cost += 1000;
+        }
+        if loss.color {
+            cost += 100;
+        }
+        cost += bits_per_channel(source).abs_diff(bits_per_channel(target)) as u32;
+        cost += target.channel_count().abs_diff(source.channel_count()) as u32;
+        cost
+    })
+}
+
+/// Converts `img` to the closest color type the target encoder accepts.
+/// Images already in a supported type are returned as they are.
+pub fn adapt_color_type(img: DynamicImage, format: ImageFormat, options: &EncodeOptions) -> DynamicImage {
+    let supported = supported_color_types(format, options);
+    match closest_color_type(img.color(), supported) {
+        Some(target) if target != img.color() => convert_color_type(&img, target),
+        _ => img,
+    }
+}
+
+/// Composites transparent images onto the background chosen in the alpha
+/// options when `format` can't store alpha. Runs before `convert_image`, which
+/// would otherwise just drop the alpha channel.
+pub fn flatten_alpha(img: DynamicImage, format: ImageFormat, options: &EncodeOptions) -> Result<DynamicImage, ConversionError> {
+    let keeps_alpha = supported_color_types(format, options).iter().any(|color| color.has_alpha());
+    if keeps_alpha || !img.color().has_alpha() || is_opaque(&img) {
+        return Ok(img);
+    }
+
+    if options.alpha.mode == AlphaMode::Fail {
+        return Err(ConversionError::TransparencyNotSupported);
+    }
+
+    let background = |x: u32, y: u32| -> [f32; 3] {
+        match options.alpha.mode {
+            AlphaMode::Checkerboard => {
+                // 8px squares of white and light gray, like most editors show transparency
+                let shade = if (x / 8 + y / 8).is_multiple_of(2) { 1.0 } else { 0.8 };
+                [shade; 3]
+            },
+            _ => options.alpha.background.map(|c| f32::from(c) / 255.0),
+        }
+    };
+
+    let rgba = img.to_rgba32f();
+    let flattened = Rgb32FImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let [br, bg, bb] = background(x, y);
+        image::Rgb([r * a + br * (1.0 - a), g * a + bg * (1.0 - a), b * a + bb * (1.0 - a)])
+    });
+
+    // go back to the source precision, the encoder step narrows it further if needed
+    let flattened = DynamicImage::ImageRgb32F(flattened);
+    Ok(match img.color() {
+        ColorType::La8 | ColorType::Rgba8 => DynamicImage::ImageRgb8(flattened.to_rgb8()),
+        ColorType::La16 | ColorType::Rgba16 => DynamicImage::ImageRgb16(flattened.to_rgb16()),
+        _ => flattened,
+    })
+}
+
+fn is_opaque(img: &DynamicImage) -> bool {
+    match img {
+        DynamicImage::ImageLumaA8(buf) => buf.pixels().all(|p| p.0[1] == u8::MAX),
+        DynamicImage::ImageRgba8(buf) => buf.pixels().all(|p| p.0[3] == u8::MAX),
+        DynamicImage::ImageLumaA16(buf) => buf.pixels().all(|p| p.0[1] == u16::MAX),
+        DynamicImage::ImageRgba16(buf) => buf.pixels().all(|p| p.0[3] == u16::MAX),
+        img => img.pixels().all(|(_, _, p)| p.0[3] == u8::MAX),
+    }
+}
+
+/// Moves pixels from the color space of the `icc` profile to sRGB, so they
+/// look right without the profile. Gray and CMYK profiles are left alone, the
+/// decoder has already turned CMYK into RGB without one.
+pub fn convert_to_srgb(img: DynamicImage, icc: &[u8]) -> Result<DynamicImage, ConversionError> {
+    let source = ColorProfile::new_from_slice(icc)?;
+    if source.color_space != DataColorSpace::Rgb {
+        return Ok(img);
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    let layout = if img.color().has_alpha() { Layout::Rgba } else { Layout::Rgb };
+    let options = TransformOptions::default();
+
+    // keep the source precision, gray images become RGB since the profile describes RGB
+    Ok(match (bits_per_channel(img.color()), layout) {
+        (8, Layout::Rgb) => {
+            let mut buf = img.into_rgb8();
+            transform_samples(&*source.create_transform_8bit(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgb8(buf)
+        },
+        (8, _) => {
+            let mut buf = img.into_rgba8();
+            transform_samples(&*source.create_transform_8bit(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgba8(buf)
+        },
+        (16, Layout::Rgb) => {
+            let mut buf = img.into_rgb16();
+            transform_samples(&*source.create_transform_16bit(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgb16(buf)
+        },
+        (16, _) => {
+            let mut buf = img.into_rgba16();
+            transform_samples(&*source.create_transform_16bit(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgba16(buf)
+        },
+        (_, Layout::Rgb) => {
+            let mut buf = img.into_rgb32f();
+            transform_samples(&*source.create_transform_f32(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgb32F(buf)
+        },
+        _ => {
+            let mut buf = img.into_rgba32f();
+            transform_samples(&*source.create_transform_f32(layout, &srgb, layout, options)?, &mut buf)?;
+            DynamicImage::ImageRgba32F(buf)
+        },
+    })
+}
+
+fn transform_samples<T: Copy + Default>(transform: &dyn TransformExecutor<T>, samples: &mut [T]) -> Result<(), CmsError> {
+    let source = samples.to_vec();
+    transform.transform(&source, samples)
+}
+
+fn convert_color_type(img: &DynamicImage, color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(img.to_rgba8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        ColorType::Rgba32F => DynamicImage::ImageRgba32F(img.to_rgba32f()),
+        _ => img.clone(),
+    }
+}
+
+fn bits_per_channel(color: ColorType) -> u16 {
+    color.bits_per_pixel() / u16::from(color.channel_count())
+}
+
+/// What is thrown away when pixels of one color type are stored as another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ColorLoss {
+    pub alpha: bool,
+    pub color: bool,
+    pub precision: bool,
+}
+
+impl ColorLoss {
+    pub fn between(source: ColorType, target: ColorType) -> Self {
+        ColorLoss {
+            alpha: source.has_alpha() && !target.has_alpha(),
+            color: source.has_color() && !target.has_color(),
+            precision: bits_per_channel(source) > bits_per_channel(target),
+        }
+    }
+
+    /// The loss `convert_image` will cause for an image of color type `source`.
+    pub fn for_target(source: ColorType, format: ImageFormat, options: &EncodeOptions) -> Self {
+        closest_color_type(source, supported_color_types(format, options))
+            .map(|target| ColorLoss::between(source, target))
+            .unwrap_or_default()
+    }
+
+    /// A short note for the image row, `None` when nothing is lost.
+    pub fn describe(&self) -> Option<String> {
+        let lost: Vec<&str> = [
+            (self.alpha, "transparency"),
+            (self.color, "color"),
+            (self.precision, "bit depth"),
+        ].into_iter().filter(|(lost, _)| *lost).map(|(_, what)| what).collect();
+
+        if lost.is_empty() {
+            None
+        } else {
+            Some(format!("Loses {}", lost.join(", ")))
+        }
+    }
+}
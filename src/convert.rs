@@ -0,0 +1,313 @@
+use std::io::{Cursor, Seek, Write};
+
+use image::{ColorType, DynamicImage, ExtendedColorType, ImageEncoder, ImageError, ImageFormat};
+use tiff::encoder::colortype;
+use tiff::encoder::{Compression, DeflateLevel, TiffEncoder, TiffValue};
+use tiff::tags::Tag;
+use tiff::TiffResult;
+
+use crate::color::adapt_color_type;
+use crate::error::ConversionError;
+use crate::options::{EncodeOptions, PnmEncoding, PnmKind, TiffCompression, WebpMode};
+use crate::vp8::encode_lossy_webp;
+
+/// Encodes `img` as `format`. The `icc` profile is embedded by the PNG, JPEG,
+/// TIFF and WebP encoders and dropped by the rest.
+pub fn convert_image(img: DynamicImage, format: ImageFormat, options: &EncodeOptions, icc: Option<&[u8]>) -> Result<Vec<u8>, ConversionError> {
+    ConversionError::check_dimensions(format, img.width(), img.height())?;
+    let img = adapt_color_type(img, format, options);
+
+    let mut buffer = Vec::new();
+    let mut cursor = Cursor::new(&mut buffer);
+
+    match format {
+        ImageFormat::Png => {
+            let mut encoder = image::codecs::png::PngEncoder::new_with_quality(
+                &mut cursor,
+                options.png.compression.into(),
+                options.png.filter.into(),
+            );
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc.to_vec()).map_err(ImageError::Unsupported)?;
+            }
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Jpeg => {
+            let mut encoder = jpeg_encoder::Encoder::new(&mut cursor, options.jpeg.quality);
+            encoder.set_sampling_factor(options.jpeg.subsampling.into());
+            if let Some(icc) = icc {
+                encoder.add_icc_profile(icc)?;
+            }
+            let color_type = match img.color() {
+                ColorType::L8 => jpeg_encoder::ColorType::Luma,
+                _ => jpeg_encoder::ColorType::Rgb,
+            };
+            // check_dimensions above keeps both sides within u16
+            encoder.encode(
+                img.as_bytes(),
+                img.width() as u16,
+                img.height() as u16,
+                color_type,
+            )?;
+        },
+        ImageFormat::Gif => {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut cursor);
+            encoder.encode(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::WebP if options.webp.mode == WebpMode::Lossy => {
+            return encode_lossy_webp(&img, options.webp.quality, icc);
+        },
+        ImageFormat::WebP => {
+            let mut encoder = image::codecs::webp::WebPEncoder::new_lossless(&mut cursor);
+            if let Some(icc) = icc {
+                encoder.set_icc_profile(icc.to_vec()).map_err(ImageError::Unsupported)?;
+            }
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Pnm => {
+            let encoder = image::codecs::pnm::PnmEncoder::new(&mut cursor)
+                .with_subtype(options.pnm.into());
+            let img = match (options.pnm.subtype, img) {
+                (PnmKind::Bitmap, DynamicImage::ImageLuma8(mut luma)) => {
+                    // the binary writer sets a black bit for 0 samples, the ASCII
+                    // one writes samples as they are and a 1 in P1 is black
+                    let dark = match options.pnm.encoding {
+                        PnmEncoding::Binary => 0,
+                        PnmEncoding::Ascii => 1,
+                    };
+                    luma.pixels_mut().for_each(|p| p.0[0] = if p.0[0] < 128 { dark } else { 1 - dark });
+                    DynamicImage::ImageLuma8(luma)
+                },
+                (_, img) => img,
+            };
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Tiff => {
+            write_tiff(&mut cursor, &img, options.tiff.compression, icc)?;
+        },
+        ImageFormat::Tga => {
+            let encoder = image::codecs::tga::TgaEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Bmp => {
+            let encoder = image::codecs::bmp::BmpEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Ico => {
+            let encoder = image::codecs::ico::IcoEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Hdr => {
+            let encoder = image::codecs::hdr::HdrEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::OpenExr => {
+            let encoder = image::codecs::openexr::OpenExrEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Farbfeld => {
+            let encoder = image::codecs::farbfeld::FarbfeldEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Avif => {
+            let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                &mut cursor,
+                options.avif.speed,
+                options.avif.quality,
+            );
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        ImageFormat::Qoi => {
+            let encoder = image::codecs::qoi::QoiEncoder::new(&mut cursor);
+            encoder.write_image(
+                img.as_bytes(),
+                img.width(),
+                img.height(),
+                ExtendedColorType::from(img.color()),
+            )?;
+        },
+        format => return Err(ConversionError::UnsupportedFormat(format)),
+    };
+
+    Ok(buffer)
+}
+
+fn write_tiff<W: Write + Seek>(w: W, img: &DynamicImage, compression: TiffCompression, icc: Option<&[u8]>) -> TiffResult<()> {
+    let compression = match compression {
+        TiffCompression::Uncompressed => Compression::Uncompressed,
+        TiffCompression::Lzw => Compression::Lzw,
+        TiffCompression::Deflate => Compression::Deflate(DeflateLevel::default()),
+        TiffCompression::PackBits => Compression::Packbits,
+    };
+    // image's own TiffEncoder is always uncompressed, so go through the tiff crate directly
+    let mut encoder = TiffEncoder::new(w)?.with_compression(compression);
+    let (width, height) = (img.width(), img.height());
+
+    match img {
+        DynamicImage::ImageLuma8(buf) =>
+            write_tiff_image::<_, colortype::Gray8>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageLuma16(buf) =>
+            write_tiff_image::<_, colortype::Gray16>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageRgb8(buf) =>
+            write_tiff_image::<_, colortype::RGB8>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageRgb16(buf) =>
+            write_tiff_image::<_, colortype::RGB16>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageRgba16(buf) =>
+            write_tiff_image::<_, colortype::RGBA16>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageRgb32F(buf) =>
+            write_tiff_image::<_, colortype::RGB32Float>(&mut encoder, width, height, buf.as_raw(), icc),
+        DynamicImage::ImageRgba32F(buf) =>
+            write_tiff_image::<_, colortype::RGBA32Float>(&mut encoder, width, height, buf.as_raw(), icc),
+        _ => // gray + alpha has no tiff color type, widen it to rgba
+            write_tiff_image::<_, colortype::RGBA8>(&mut encoder, width, height, img.to_rgba8().as_raw(), icc),
+    }
+}
+
+fn write_tiff_image<W, C>(
+    encoder: &mut TiffEncoder<W>,
+    width: u32,
+    height: u32,
+    data: &[C::Inner],
+    icc: Option<&[u8]>,
+) -> TiffResult<()>
+where
+    W: Write + Seek,
+    C: colortype::ColorType,
+    [C::Inner]: TiffValue,
+{
+    let mut image = encoder.new_image::<C>(width, height)?;
+    if let Some(icc) = icc {
+        image.encoder().write_tag(Tag::IccProfile, icc)?;
+    }
+    image.write_data(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgba, RgbaImage};
+    use super::*;
+    use crate::options::{AvifOptions, PnmOptions, WebpOptions};
+
+    fn gradient() -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 24, |x, y| {
+            Rgba([(x * 8) as u8, (y * 10) as u8, ((x + y) * 4) as u8, 255 - (x * 4) as u8])
+        }))
+    }
+
+    fn webp_options(mode: WebpMode, quality: u8) -> EncodeOptions {
+        EncodeOptions { webp: WebpOptions { mode, quality }, ..Default::default() }
+    }
+
+    #[test]
+    fn pbm_round_trip() {
+        // dark left half, light right half
+        let img = DynamicImage::ImageLuma8(image::GrayImage::from_fn(10, 3, |x, _| image::Luma([if x < 5 { 20 } else { 230 }])));
+
+        for encoding in [PnmEncoding::Binary, PnmEncoding::Ascii] {
+            let options = EncodeOptions { pnm: PnmOptions { subtype: PnmKind::Bitmap, encoding }, ..Default::default() };
+            let encoded = convert_image(img.clone(), ImageFormat::Pnm, &options, None).unwrap();
+            let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::Pnm).unwrap().to_luma8();
+
+            let (dark, light) = (decoded.get_pixel(0, 0)[0], decoded.get_pixel(9, 2)[0]);
+            assert!(dark < light, "{encoding:?}: {dark} is not darker than {light}");
+            assert!(decoded.enumerate_pixels().all(|(x, _, p)| p[0] == if x < 5 { dark } else { light }), "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn jpeg_refuses_sides_over_u16() {
+        let img = DynamicImage::ImageLuma8(image::GrayImage::new(65536, 1));
+        assert_eq!(
+            convert_image(img, ImageFormat::Jpeg, &EncodeOptions::default(), None),
+            Err(ConversionError::DimensionLimitsExceeded { width: 65536, height: 1, max: 65535 }),
+        );
+    }
+
+    #[test]
+    fn avif_writes_an_avif_file() {
+        let options = EncodeOptions { avif: AvifOptions { speed: 10, quality: 60 }, ..Default::default() };
+        let encoded = convert_image(gradient(), ImageFormat::Avif, &options, None).unwrap();
+        assert_eq!(&encoded[4..12], b"ftypavif");
+    }
+
+    #[test]
+    fn webp_lossless_round_trip() {
+        let img = gradient();
+        let encoded = convert_image(img.clone(), ImageFormat::WebP, &webp_options(WebpMode::Lossless, 80), None).unwrap();
+        assert!(!encoded.is_empty());
+
+        let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn webp_lossy_round_trip() {
+        let img = gradient();
+        let encoded = convert_image(img.clone(), ImageFormat::WebP, &webp_options(WebpMode::Lossy, 90), None).unwrap();
+        assert!(encoded.windows(4).any(|chunk| chunk == b"VP8 "));
+
+        let decoded = image::load_from_memory_with_format(&encoded, ImageFormat::WebP).unwrap();
+        assert_eq!(decoded.dimensions(), img.dimensions());
+        for (original, lossy) in img.to_rgba8().pixels().zip(decoded.to_rgba8().pixels()) {
+            for channel in 0..3 {
+                assert!(original[channel].abs_diff(lossy[channel]) <= 16);
+            }
+            assert_eq!(original[3], lossy[3]);
+        }
+    }
+}
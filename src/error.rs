@@ -0,0 +1,102 @@
+use std::fmt;
+
+use image::error::UnsupportedErrorKind;
+use image::{ExtendedColorType, ImageError, ImageFormat};
+use serde::{Deserialize, Serialize};
+
+use crate::serde_image;
+
+/// Why an image could not be converted to its target format.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ConversionError {
+    /// The target encoder can't store pixels of this color type.
+    UnsupportedColorType(#[serde(with = "serde_image::extended_color_type")] ExtendedColorType),
+    /// There is no encoder for the target format.
+    UnsupportedFormat(#[serde(with = "serde_image::image_format")] ImageFormat),
+    /// The encoder failed while writing, the message comes from the encoder itself.
+    Encoder(String),
+    /// The image is larger than the target format can describe.
+    DimensionLimitsExceeded { width: u32, height: u32, max: u32 },
+    /// The image has transparent pixels, the target format has no alpha and
+    /// flattening was turned off.
+    TransparencyNotSupported,
+    /// The embedded color profile couldn't be read or applied.
+    ColorProfile(String),
+    /// The uploaded file couldn't be decoded again for the conversion.
+    Decode(String),
+    /// The worker running the conversion died, e.g. out of memory.
+    WorkerCrashed,
+    /// A resize step asks for more pixels than can be held in memory.
+    TooManyPixels { width: u32, height: u32, max: u64 },
+}
+
+impl ConversionError {
+    /// Fails with `DimensionLimitsExceeded` if either side is over the largest
+    /// size `format` can store.
+    pub fn check_dimensions(format: ImageFormat, width: u32, height: u32) -> Result<(), Self> {
+        let max = match format {
+            ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::Tga => u32::from(u16::MAX),
+            ImageFormat::WebP => 16384,
+            ImageFormat::Ico => 256,
+            _ => return Ok(()),
+        };
+
+        if width > max || height > max {
+            return Err(ConversionError::DimensionLimitsExceeded { width, height, max });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::UnsupportedColorType(color) =>
+                write!(f, "color type {color:?} is not supported by the target format"),
+            ConversionError::UnsupportedFormat(format) =>
+                write!(f, "can't encode {} images", format.extensions_str()[0]),
+            ConversionError::Encoder(message) => write!(f, "encoder error: {message}"),
+            ConversionError::DimensionLimitsExceeded { width, height, max } =>
+                write!(f, "{width}x{height} is too large, the target format allows at most {max}x{max}"),
+            ConversionError::TransparencyNotSupported =>
+                write!(f, "image has transparency but the target format has no alpha channel"),
+            ConversionError::ColorProfile(message) => write!(f, "color profile error: {message}"),
+            ConversionError::Decode(message) => write!(f, "decoding error: {message}"),
+            ConversionError::WorkerCrashed => write!(f, "conversion worker crashed"),
+            ConversionError::TooManyPixels { width, height, max } =>
+                write!(f, "resizing to {width}x{height} is over the limit of {} megapixels", max / 1_000_000),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<ImageError> for ConversionError {
+    fn from(err: ImageError) -> Self {
+        match err {
+            ImageError::Unsupported(ref unsupported) => match unsupported.kind() {
+                UnsupportedErrorKind::Color(color) => ConversionError::UnsupportedColorType(color),
+                _ => ConversionError::Encoder(err.to_string()),
+            },
+            err => ConversionError::Encoder(err.to_string()),
+        }
+    }
+}
+
+impl From<jpeg_encoder::EncodingError> for ConversionError {
+    fn from(err: jpeg_encoder::EncodingError) -> Self {
+        ConversionError::Encoder(err.to_string())
+    }
+}
+
+impl From<tiff::TiffError> for ConversionError {
+    fn from(err: tiff::TiffError) -> Self {
+        ConversionError::Encoder(err.to_string())
+    }
+}
+
+impl From<moxcms::CmsError> for ConversionError {
+    fn from(err: moxcms::CmsError) -> Self {
+        ConversionError::ColorProfile(err.to_string())
+    }
+}
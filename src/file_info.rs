@@ -0,0 +1,138 @@
+//! What we know about an uploaded file or a converted one.
+
+use std::io::Cursor;
+
+use image::{ExtendedColorType, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+
+use crate::metadata::Metadata;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileInfo {
+    pub name: String,
+    pub file_type: ImageFormat,
+    pub metadata: Metadata,
+    pub bytes: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+    pub color: ExtendedColorType, // as stored in the file, before decoding widened it
+}
+
+impl Default for FileInfo {
+    fn default() -> Self {
+        FileInfo {
+            name: String::default(),
+            file_type: ImageFormat::Png,
+            metadata: Metadata::default(),
+            bytes: Vec::default(),
+            width: 0,
+            height: 0,
+            color: ExtendedColorType::Rgba8,
+        }
+    }
+}
+
+impl FileInfo {
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn bit_depth(&self) -> u16 {
+        match self.color.channel_count() {
+            0 => 0,
+            channels => self.color.bits_per_pixel() / channels as u16,
+        }
+    }
+
+    /// e.g. `2.4 MB PNG`
+    pub fn short_description(&self) -> String {
+        format!("{} {}", format_size(self.size()), format_name(self.file_type))
+    }
+
+    /// e.g. `2.4 MB PNG, 1920x1080, Rgba8, 8 bit`
+    pub fn description(&self) -> String {
+        format!("{}, {}x{}, {:?}, {} bit", self.short_description(), self.width, self.height, self.color, self.bit_depth())
+    }
+
+    /// e.g. `2.4 MB PNG -> 310 KB JPEG, -87%`
+    pub fn size_change(&self, output: &FileInfo) -> String {
+        let change = match self.size() {
+            0 => String::new(),
+            before => {
+                let percent = (output.size() as f64 / before as f64 - 1.0) * 100.0;
+                format!(", {percent:+.0}%")
+            },
+        };
+        format!("{} -> {}{change}", self.short_description(), output.short_description())
+    }
+}
+
+/// Dimensions and color type from a file's header, without decoding the pixels.
+pub fn read_header(file_type: ImageFormat, bytes: &[u8]) -> ImageResult<(u32, u32, ExtendedColorType)> {
+    let decoder = ImageReader::with_format(Cursor::new(bytes), file_type).into_decoder()?;
+    let (width, height) = decoder.dimensions();
+    Ok((width, height, decoder.original_color_type()))
+}
+
+pub fn format_name(format: ImageFormat) -> String {
+    format!("{format:?}").to_uppercase()
+}
+
+/// Sizes in decimal units, the way file managers show them.
+pub fn format_size(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1000 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if size < 10.0 {
+        format!("{size:.1} {}", UNITS[unit])
+    } else {
+        format!("{size:.0} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+    use super::*;
+
+    fn info(size: usize, file_type: ImageFormat) -> FileInfo {
+        FileInfo { file_type, bytes: vec![0; size], ..Default::default() }
+    }
+
+    #[test]
+    fn bit_depth_is_per_channel() {
+        let info = FileInfo { color: ExtendedColorType::Rgba16, ..Default::default() };
+        assert_eq!(info.bit_depth(), 16);
+    }
+
+    #[test]
+    fn sizes_read_like_a_file_manager() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(2_400_000), "2.4 MB");
+        assert_eq!(format_size(310_000), "310 KB");
+    }
+
+    #[test]
+    fn size_change_shows_the_percentage() {
+        let change = info(2_400_000, ImageFormat::Png).size_change(&info(310_000, ImageFormat::Jpeg));
+        assert_eq!(change, "2.4 MB PNG -> 310 KB JPEG, -87%");
+    }
+
+    #[test]
+    fn header_gives_dimensions_and_color() {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(5, 3))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+
+        assert_eq!(read_header(ImageFormat::Png, &bytes).unwrap(), (5, 3, ExtendedColorType::Rgb8));
+    }
+}
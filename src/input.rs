@@ -0,0 +1,94 @@
+//! Turns drops and pastes into the `(path, blob)` pairs `process_files` reads.
+//! The path is relative to what was picked or dropped, `dir/file.png`.
+
+use std::rc::Rc;
+
+use image::ImageFormat;
+use js_sys::Array;
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::JsCast;
+use web_sys::{Blob, DataTransfer, File, FileSystemDirectoryEntry, FileSystemDirectoryReader, FileSystemEntry, FileSystemFileEntry};
+
+pub type Upload = (String, Blob);
+
+/// Calls `on_file` for every file in a drop. Dropped directories are walked,
+/// which happens asynchronously, so files arrive one by one.
+pub fn dropped_files(transfer: &DataTransfer, on_file: impl Fn(Upload) + 'static) {
+    let on_file: Rc<dyn Fn(Upload)> = Rc::new(on_file);
+    let items = transfer.items();
+
+    // entries have to be taken before the drop handler returns, the list is emptied after that
+    let entries: Vec<_> = (0..items.length())
+        .filter_map(|i| items.get(i))
+        .filter(|item| item.kind() == "file")
+        .filter_map(|item| match item.webkit_get_as_entry() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            _ => item.get_as_file().ok().flatten().map(Err),
+        })
+        .collect();
+
+    for entry in entries {
+        match entry {
+            Ok(entry) => walk_entry(entry, on_file.clone()),
+            // no entry API, plain files still work
+            Err(file) => on_file((file.name(), file.into())),
+        }
+    }
+}
+
+fn walk_entry(entry: FileSystemEntry, on_file: Rc<dyn Fn(Upload)>) {
+    if entry.is_file() {
+        let name = entry.full_path(); // relative to the drop, keeps the dropped folder's structure
+        let callback = Closure::once_into_js(move |file: File| on_file((name, file.into())));
+        entry.unchecked_into::<FileSystemFileEntry>().file_with_callback(callback.unchecked_ref());
+    } else if entry.is_directory() {
+        let reader = entry.unchecked_into::<FileSystemDirectoryEntry>().create_reader();
+        read_directory(reader, on_file);
+    }
+}
+
+// readEntries hands out a directory in batches and signals the end with an empty one
+fn read_directory(reader: FileSystemDirectoryReader, on_file: Rc<dyn Fn(Upload)>) {
+    let next = reader.clone();
+    let callback = Closure::once_into_js(move |entries: Array| {
+        if entries.length() == 0 {
+            return;
+        }
+        entries.iter().for_each(|entry| walk_entry(entry.unchecked_into(), on_file.clone()));
+        read_directory(next, on_file);
+    });
+
+    if reader.read_entries_with_callback(callback.unchecked_ref()).is_err() {
+        web_sys::console::error_1(&"Failed to read dropped directory".into());
+    }
+}
+
+/// The file's path inside a folder upload, or just its name otherwise.
+pub fn relative_path(file: &File) -> String {
+    // web-sys has no binding for `webkitRelativePath`
+    js_sys::Reflect::get(file, &"webkitRelativePath".into())
+        .ok()
+        .and_then(|path| path.as_string())
+        .filter(|path| !path.is_empty())
+        .unwrap_or_else(|| file.name())
+}
+
+/// Images in a paste, named `pasted-<timestamp>.<ext>` since screenshots
+/// come without a useful name.
+pub fn pasted_images(transfer: &DataTransfer) -> Vec<Upload> {
+    let items = transfer.items();
+    let timestamp = js_sys::Date::now() as u64;
+
+    (0..items.length())
+        .filter_map(|i| items.get(i))
+        .filter(|item| item.kind() == "file" && item.type_().starts_with("image/"))
+        .filter_map(|item| item.get_as_file().ok().flatten())
+        .enumerate()
+        .map(|(index, file)| {
+            // from the MIME type, `image/svg+xml` would otherwise end up as `.svg+xml`
+            let ext = ImageFormat::from_mime_type(file.type_()).map_or("png", |format| format.extensions_str()[0]);
+            let suffix = if index == 0 { String::new() } else { format!("-{index}") };
+            (format!("pasted-{timestamp}{suffix}.{ext}"), file.into())
+        })
+        .collect()
+}
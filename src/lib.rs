@@ -1,4 +1,17 @@
-use wasm_bindgen::prelude::wasm_bindgen;
-
-#[wasm_bindgen(start)]
-pub async fn run() {}
+pub mod archive;
+pub mod color;
+pub mod convert;
+pub mod error;
+pub mod file_info;
+pub mod metadata;
+pub mod naming;
+pub mod options;
+pub mod preset;
+pub mod protocol;
+pub mod recipe;
+pub mod resize;
+mod serde_image;
+pub mod session;
+pub mod transform;
+pub mod upload;
+mod vp8;
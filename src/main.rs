@@ -1,12 +1,16 @@
 mod app;
+mod input;
 mod js;
+mod preset_bar;
+mod recipe_editor;
+mod scheduler;
+mod session_store;
+mod worker_pool;
 
 use std::rc::Rc;
-use std::sync::mpsc;
-use async_std::prelude::StreamExt;
+use futures::StreamExt;
 use leptos::*;
 use leptos::mount_to_body;
-use leptos::prelude::*;
 use image::{DynamicImage, ImageError, ImageFormat};
 use base64::{Engine};
 use base64::engine::general_purpose;
@@ -14,157 +18,326 @@ use image::imageops::FilterType;
 use js_sys::Uint8Array;
 use leptos::{IntoView};
 use leptos_mview::mview;
-use tar::{Builder, Header};
 use uuid::Uuid;
-use crate::app::{convert_image, App};
+use wasm_bindgen_futures::spawn_local;
+use crate::app::App;
+use crate::scheduler::Scheduler;
+use crate::session_store::SessionStore;
+use crate::worker_pool::WorkerPool;
+use web_image_converter::archive::{write_archive, ArchiveEntry, ArchiveFormat};
+use web_image_converter::color::ColorLoss;
+use web_image_converter::error::ConversionError;
+use web_image_converter::file_info::{read_header, FileInfo};
+use web_image_converter::metadata::Metadata;
+use web_image_converter::naming::{file_stem, resolve_collisions, CollisionPolicy, NameFields, NameOutcome, NameTemplate};
+use web_image_converter::options::EncodeOptions;
+use web_image_converter::upload::UploadError;
+use web_image_converter::protocol::JobStage;
+use web_image_converter::recipe::Recipe;
 use crate::js::downloadFile;
 
 
 #[derive(Clone, Debug, PartialEq, Default)]
 pub struct DisplayImage {
     id: String,
-    is_completed: bool,
+    status: RwSignal<ImageStatus>,
     is_selected: RwSignal<bool>,
     name: String,
+    folder: String, // relative folder the file was uploaded from, empty at the root
     preview: String,
     in_filetype: &'static str,
     out_filetype: Option<ImageFormat>,
+    out_extension: &'static str,
+    encode_options: EncodeOptions,
+    recipe: Recipe,
+    auto_oriented: bool, // whether `image` was turned upright on upload
     time_completed: Option<String>, // FOR NOW this is string todo
     image: DynamicImage,
-    result: Vec<u8>,
-    
+
     in_file: FileInfo,
-    out_file: Option<FileInfo>,
+    out_file: Option<FileInfo>, // set once the image is converted
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub struct FileInfo {
-    name: String,
-    file_type: ImageFormat,
-    metadata: Option<u8>, // TODO
-    bytes: Vec<u8>
+/// Where an image is in the conversion queue.
+#[derive(Clone, Debug, PartialEq, Default)]
+pub enum ImageStatus {
+    #[default]
+    Pending,
+    Decoding,
+    Encoding,
+    Done,
+    Failed(ConversionError),
+    Cancelled,
 }
 
-impl Default for FileInfo {
-    fn default() -> Self {
-        FileInfo {
-            name: String::default(),
-            file_type: ImageFormat::Png,
-            metadata: None,
-            bytes: Vec::default(),
+impl DisplayImage {
+    /// A freshly decoded upload, not queued yet.
+    fn new(name: String, folder: String, in_file: FileInfo, image: DynamicImage, auto_oriented: bool, buffer: &mut Vec<u8>) -> DisplayImage {
+        DisplayImage {
+            id: generate_unique_key(),
+            status: create_rw_signal(ImageStatus::Pending),
+            is_selected: create_rw_signal(false),
+            name,
+            folder,
+            in_filetype: in_file.file_type.extensions_str()[0],
+            out_filetype: None,
+            out_extension: "",
+            encode_options: EncodeOptions::default(),
+            recipe: Recipe::default(),
+            auto_oriented,
+            time_completed: None,
+            preview: generate_sample_image(&image, 64, buffer),
+            image,
+            in_file,
+            out_file: None,
+        }
+    }
+
+    /// The converted file's bytes, empty until it is converted.
+    pub fn encoded(&self) -> &[u8] {
+        self.out_file.as_ref().map(|file| file.bytes.as_slice()).unwrap_or_default()
+    }
+
+    fn output_info(&self, encoded: Vec<u8>) -> FileInfo {
+        let format = self.out_filetype.expect("converted images have a target format");
+        let name = format!("{}.{}", file_stem(&self.name), self.out_extension);
+
+        // not every format we write can be read back, the image we encoded is close enough then
+        let (width, height) = self.recipe.output_size(self.image.width(), self.image.height());
+        let (width, height, color) = read_header(format, &encoded)
+            .unwrap_or((width, height, self.image.color().into()));
+        let metadata = Metadata::read(&encoded);
+        FileInfo { name, file_type: format, metadata, bytes: encoded, width, height, color }
+    }
+
+    /// The path of the converted file in the download, `index` counts from 1.
+    pub fn output_name(&self, template: &NameTemplate, index: usize, date: &str, keep_folders: bool) -> String {
+        let format = self.out_filetype.expect("converted images have a target format");
+        let name = template.render(&NameFields {
+            stem: file_stem(&self.name),
+            format,
+            extension: self.out_extension,
+            width: self.out_file.as_ref().map_or(self.image.width(), |file| file.width),
+            height: self.out_file.as_ref().map_or(self.image.height(), |file| file.height),
+            index,
+            date,
+            data: self.encoded(),
+            quality: self.encode_options.quality(format),
+        });
+
+        if keep_folders && !self.folder.is_empty() {
+            format!("{}/{name}", self.folder)
+        } else {
+            name
+        }
+    }
+}
+
+impl ImageStatus {
+    /// Percentage for the progress bar, `None` once the image left the queue.
+    fn progress(&self) -> Option<u8> {
+        match self {
+            ImageStatus::Pending => Some(0),
+            ImageStatus::Decoding => Some(33),
+            ImageStatus::Encoding => Some(66),
+            _ => None,
+        }
+    }
+
+    fn is_queued(&self) -> bool {
+        self.progress().is_some()
+    }
+
+    fn label(&self) -> String {
+        match self {
+            ImageStatus::Pending => "Pending".to_string(),
+            ImageStatus::Decoding => "Decoding".to_string(),
+            ImageStatus::Encoding => "Encoding".to_string(),
+            ImageStatus::Done => "Done".to_string(),
+            ImageStatus::Failed(err) => format!("Failed: {err}"),
+            ImageStatus::Cancelled => "Cancelled".to_string(),
         }
     }
 }
 
+impl From<JobStage> for ImageStatus {
+    fn from(stage: JobStage) -> Self {
+        match stage {
+            JobStage::Decoding => ImageStatus::Decoding,
+            JobStage::Encoding => ImageStatus::Encoding,
+        }
+    }
+}
 
+/// An upload that couldn't be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RejectedFile {
+    id: String,
+    name: String,
+    reason: UploadError,
+}
 
 #[derive(Clone, Default)]
 struct AppState {
     input_files: RwSignal<Vec<DisplayImage>>,
     queued_files: RwSignal<Vec<DisplayImage>>,
     output_files: RwSignal<Vec<DisplayImage>>,
+    rejected_files: RwSignal<Vec<RejectedFile>>,
+    auto_orient: RwSignal<bool>, // rotate uploads as their EXIF orientation says
+    pool: Rc<WorkerPool>,
+    scheduler: Rc<Scheduler>,
+    session: Rc<SessionStore>,
 }
 
 impl AppState {
-    fn detect_format(bytes: &[u8]) -> Option<ImageFormat> {
-        let format = image::guess_format(bytes).ok()?;
-        Some(format)
-    }
-
-    pub fn generate_thumbnail_str(file_info: FileInfo) -> String {
-        let img = image::load_from_memory(&file_info.bytes)
-            .expect("Failed to load image from memory");
-
-        let thumbnail = img.resize(64, 64, FilterType::Lanczos3);
-
-        let mut buffer = Vec::new();
-        thumbnail.write_to(&mut std::io::Cursor::new(&mut buffer), ImageFormat::Png)
-            .expect("Failed to write image to buffer");
-
-        let base64 = general_purpose::STANDARD.encode(&buffer);
-        format!("data:image/png;base64,{}", base64)
-    }
-
-    pub fn queue_selected(&self, output_format: ImageFormat) {
+    pub fn queue_selected(&self, output_format: ImageFormat, extension: &'static str, encode_options: EncodeOptions, recipe: Recipe) {
         self.queued_files.update(|queued| {
             let mut selected: Vec<DisplayImage> = self.input_files.get().iter().filter(|img| img.is_selected.get()).cloned().collect();
-            selected.iter_mut().for_each(|img| img.out_filetype = Some(output_format));
+            selected.iter_mut().for_each(|img| {
+                img.out_filetype = Some(output_format);
+                img.out_extension = extension;
+                img.encode_options = encode_options;
+                img.recipe = recipe.clone();
+                img.status.set(ImageStatus::Pending);
+            });
             queued.extend(selected);
             self.input_files.update(|queue| queue.retain(|image| !image.is_selected.get()));
         });
+        self.scheduler.wake();
+    }
+
+    /// Encodes a queued image on the worker pool, keeping its status in step.
+    pub async fn convert(&self, img: &DisplayImage) -> Result<Vec<u8>, ConversionError> {
+        let format = img.out_filetype.expect("queued images have a target format");
+        let (status, scheduler, id) = (img.status, self.scheduler.clone(), img.id.clone());
+        let on_stage = move |stage: JobStage| {
+            if !scheduler.is_cancelled(&id) {
+                status.set(stage.into());
+            }
+        };
+        self.pool.convert(&img.in_file.bytes, img.auto_oriented, &img.recipe, format, img.encode_options, on_stage).await
     }
 
-    pub fn download_selected(&self) {
-        if self.output_files.get().is_empty() {
-            return;
+    /// Takes an image out of the queue and puts it back with the uploads.
+    pub fn cancel(&self, id: &str) {
+        let mut cancelled = None;
+        self.queued_files.update(|queued| {
+            if let Some(index) = queued.iter().position(|img| img.id == id) {
+                cancelled = Some(queued.remove(index));
+            }
+        });
+
+        if let Some(mut img) = cancelled {
+            self.scheduler.cancel(id);
+            img.status.set(ImageStatus::Cancelled);
+            img.out_filetype = None;
+            self.input_files.update(|input| input.push(img));
         }
+    }
 
-        let buffer = std::io::Cursor::new(Vec::new());
-        let mut a = Builder::new(buffer);
+    pub fn cancel_all(&self) {
+        let ids: Vec<String> = self.queued_files.with_untracked(|queued| queued.iter().map(|img| img.id.clone()).collect());
+        ids.iter().for_each(|id| self.cancel(id));
+    }
 
+    /// The converted images that would go into the download, in order.
+    pub fn selected_outputs(&self) -> Vec<DisplayImage> {
         self.output_files.get()
+            .into_iter()
+            .filter(|img| img.is_selected.get() && img.status.get() == ImageStatus::Done)
+            .collect()
+    }
+
+    /// Final names for `outputs` in download order, with collisions resolved.
+    pub fn output_names(outputs: &[DisplayImage], template: &NameTemplate, policy: CollisionPolicy, keep_folders: bool) -> Vec<NameOutcome> {
+        let date = today();
+        let names: Vec<(String, &[u8])> = outputs
             .iter()
-            .filter(|img| img.is_selected.get())
-            .for_each(|img| {
-                let old_termination = format!(".{}", img.in_filetype);
-
-                // strip file ext
-                let file_name = if img.name.ends_with(&old_termination) {
-                    img.name.strip_suffix(&old_termination)
-                        .unwrap_or(&img.name)
-                        .to_string()
-                } else {
-                    img.name.to_string()
-                };
+            .enumerate()
+            .map(|(index, img)| (img.output_name(template, index + 1, &date, keep_folders), img.encoded()))
+            .collect();
+        resolve_collisions(&names, policy)
+    }
 
-                // Truncate file name to a maximum of 64 characters
-                let file_name = file_name.chars().take(64).collect::<String>();
+    /// Downloads the selected outputs and returns a line for every file that
+    /// was renamed or skipped to avoid a name collision, or why the archive
+    /// couldn't be written.
+    pub fn download_selected(&self, archive: ArchiveFormat, template: &NameTemplate, policy: CollisionPolicy, keep_folders: bool) -> std::io::Result<Vec<String>> {
+        let selected = self.selected_outputs();
+        if selected.is_empty() {
+            return Ok(Vec::new());
+        }
 
-                let mut header = Header::new_gnu();
-                header.set_path(format!("{file_name}.{}", img.out_filetype.unwrap()
-                    .extensions_str()[0])).unwrap(); // TODO can add support for other terminations in additional settings
-                header.set_size(img.result.len() as u64);
-                header.set_mode(0o644);
-                // header.set_metadata()
-                header.set_cksum();
+        let outcomes = Self::output_names(&selected, template, policy, keep_folders);
+        let entries: Vec<ArchiveEntry> = selected
+            .iter()
+            .zip(&outcomes)
+            .filter_map(|(img, outcome)| Some(ArchiveEntry {
+                path: outcome.name()?.to_string(),
+                data: img.encoded(),
+            }))
+            .collect();
 
-                a.append(&header, img.result.as_slice()).unwrap()
-            });
+        let data = write_archive(archive, &entries)?;
+
+        // Convert archive data to Uint8Array
+        let js_data = Uint8Array::from(data.as_slice());
+
+        downloadFile(archive.file_name(), js_data);
+
+        Ok(outcomes.iter().filter_map(NameOutcome::describe).collect())
+    }
 
-        // Get the TAR data from the buffer
-        let tar_data = a.into_inner().unwrap().into_inner();
 
-        // Convert tar data to Uint8Array
-        let js_data = Uint8Array::from(tar_data.as_slice());
+    /// Runs the conversion queue for the lifetime of the app. Images are
+    /// started in the order they were queued, as many at a time as the pool
+    /// has workers, and the task sleeps whenever there's nothing to start.
+    pub async fn step_queue(self) {
+        let mut wakeups = self.scheduler.take_wakeups();
+        while wakeups.next().await.is_some() {
+            while !self.scheduler.is_paused() && self.scheduler.running_count() < self.pool.capacity() {
+                let next = self.queued_files.with_untracked(|queued| {
+                    queued.iter().find(|file| !self.scheduler.is_running(&file.id)).cloned()
+                });
+                let Some(file) = next else {
+                    break;
+                };
 
-        downloadFile("output.tar", js_data.into());
+                self.scheduler.start(&file.id);
+                spawn_local(self.clone().process(file));
+            }
+        }
     }
 
+    async fn process(self, mut file: DisplayImage) {
+        let encoded = self.convert(&file).await;
+
+        let id = file.id.clone();
+        if self.scheduler.finish(&id) {
+            return; // already back in the uploaded list
+        }
+
+        // a failed image still moves on so the rest keep going
+        match encoded {
+            Ok(encoded) => {
+                file.out_file = Some(file.output_info(encoded));
+                file.status.set(ImageStatus::Done);
+            },
+            Err(err) => file.status.set(ImageStatus::Failed(err)),
+        }
 
-    // pub async fn step_queue(&self, mut rx: mpsc::Receiver<()>) {
-    //     while let Some(_) = rx.next().await {
-    //         let queued = self.queued_files;
-    //
-    //         if let Some(mut file) = queued.get().pop() {
-    //             let out_type = file.out_filetype.clone().unwrap();
-    //             let encoded = convert_image(file.image.clone(), out_type).await;
-    //
-    //             file.result = encoded.expect("Failed to process a picture");
-    //
-    //             self.output_files.update(|output| {
-    //                 output.push(file);
-    //             });
-    //         }
-    //     }
-    // }
+        self.queued_files.update(|queued| queued.retain(|queued| queued.id != id));
+        self.output_files.update(|output| output.push(file));
+    }
 
 }
 
-fn generate_sample_image(img: &DynamicImage, buffer: &mut Vec<u8>) -> String {
+/// A PNG data URL of `img` scaled to fit in `size`x`size`.
+fn generate_sample_image(img: &DynamicImage, size: u32, buffer: &mut Vec<u8>) -> String {
     buffer.clear(); // Clear the buffer for reuse
 
-    // Resize the image
-    let resized = img.resize(64, 64, FilterType::Lanczos3);
+    // Resize the image, as 8-bit since PNG can't hold the float images HDR and EXR decode to
+    let resized = DynamicImage::ImageRgba8(img.resize(size, size, FilterType::Lanczos3).to_rgba8());
 
     // Create a Cursor wrapping the buffer
     let mut cursor = std::io::Cursor::new(buffer);
@@ -183,6 +356,12 @@ fn generate_sample_image(img: &DynamicImage, buffer: &mut Vec<u8>) -> String {
     format!("data:image/png;base64,{}", base64)
 }
 
+/// The local date as `YYYY-MM-DD`.
+pub fn today() -> String {
+    let now = js_sys::Date::new_0();
+    format!("{:04}-{:02}-{:02}", now.get_full_year(), now.get_month() + 1, now.get_date())
+}
+
 pub fn generate_unique_key() -> String {
     Uuid::new_v4().to_string()
 }
@@ -190,16 +369,19 @@ pub fn generate_unique_key() -> String {
 impl IntoView for DisplayImage {
     fn into_view(self) -> View {
         let name = self.name.clone();
-        let is_completed = self.is_completed;
+        let status = self.status;
         let preview = self.preview.clone();
         let completed_time = self.time_completed.clone();
+        let color_loss = self.out_filetype.and_then(|format| {
+            ColorLoss::for_target(self.image.color(), format, &self.encode_options).describe()
+        });
 
-        let is_selected = self.is_selected.clone();
+        let is_selected = self.is_selected;
 
         let conversion_str = match &self.out_filetype {
-            None => format!("{}", self.in_filetype),
-            Some(out_ext) => format!("{} -> {}",
-                                     self.in_filetype, out_ext.extensions_str()[0]),
+            None => self.in_filetype.to_string(),
+            Some(_) => format!("{} -> {}",
+                                     self.in_filetype, self.out_extension),
         };
 
         let on_checkbox=move |ev| {
@@ -210,18 +392,47 @@ impl IntoView for DisplayImage {
             is_selected.set(!is_selected.get());
         };
 
+        let id = self.id.clone();
+        let on_cancel = move |ev: ev::MouseEvent| {
+            ev.stop_propagation();
+            use_context::<AppState>().expect("AppState not provided").cancel(&id);
+        };
 
+        // uploads start out Pending too but aren't waiting on anything yet
+        let in_queue = self.out_filetype.is_some();
+        let is_queued = move || in_queue && status.get().is_queued();
+        let status_label = move || match status.get() {
+            ImageStatus::Pending if !in_queue => String::new(),
+            status => status.label(),
+        };
+
+        let status_class = move || match status.get() {
+            ImageStatus::Failed(_) => "text-red-800",
+            ImageStatus::Cancelled => "text-gray-300",
+            _ => "",
+        };
 
-        let finish_time = completed_time.unwrap_or_else(|| String::new());
+
+
+        let finish_time = completed_time.unwrap_or_default();
+
+        let (show_info, set_show_info) = create_signal(false);
+        let toggle_info = move |ev: ev::MouseEvent| {
+            ev.stop_propagation();
+            set_show_info.update(|shown| *shown = !*shown);
+        };
+        let in_info = format!("In: {}", self.in_file.description());
+        let out_info = self.out_file.as_ref().map(|out| format!("Out: {}", out.description()));
+        let size_change = self.out_file.as_ref().map(|out| self.in_file.size_change(out));
+        let in_metadata = self.in_file.metadata.describe().map(|exif| format!("In {exif}"));
+        let out_metadata = self.out_file.as_ref().and_then(|out| out.metadata.describe()).map(|exif| format!("Out {exif}"));
 
         let element =
         mview! {
-            div class="flex flex-row align-middle w-full h-20 hover:bg-blue-700" on:click={on_clicked} {
-                Show when=[!is_completed] fallback=[view! {""}] {
-                    div class="flex items-center justify-center pl-2" {
-                        label class="custom-checkbox inline-flex" {
-                            input type="checkbox" checked={is_selected.get()} on:input={on_checkbox}; {}
-                        }
+            div class="relative flex flex-row align-middle w-full h-20 hover:bg-blue-700" on:click={on_clicked} {
+                div class="flex items-center justify-center pl-2" {
+                    label class="custom-checkbox inline-flex" {
+                        input type="checkbox" checked={is_selected.get()} on:input={on_checkbox}; {}
                     }
                 }
                 img src={preview} class="m-2 h-16 w-16 bg-red-800" {
@@ -230,9 +441,31 @@ impl IntoView for DisplayImage {
                 div class="mt-1 w-full h-full overflow-hidden" {
                     p {{name}}
                     p {{conversion_str}}
+                    p class="text-yellow-300" {{color_loss}}
                     p {{finish_time}}
+                    p class={status_class} {{status_label}}
+                    Show when=[is_queued()] {
+                        progress class="w-full" max="100" value={move || status.get().progress().unwrap_or(100)};
+                    }
                     hr class="w-full border-t border-gray-300";
                 }
+                Show when=[is_queued()] {
+                    div class="flex items-center pr-2" {
+                        button class="px-2 bg-button text-sm" on:click={on_cancel.clone()} {"Cancel"}
+                    }
+                }
+                div class="flex items-center pr-2" {
+                    button class="px-2 bg-button text-sm" title="File info" on:click={toggle_info} {"i"}
+                }
+                Show when=[show_info.get()] {
+                    div class="absolute right-0 top-full z-10 p-2 bg-secondary border-2 text-sm" {
+                        p {{in_info.clone()}}
+                        p {{out_info.clone()}}
+                        p {{in_metadata.clone()}}
+                        p {{out_metadata.clone()}}
+                        p class="font-bold" {{size_change.clone()}}
+                    }
+                }
             }
         };
 
@@ -0,0 +1,330 @@
+//! Metadata carried over from the uploaded file. Decoding into a
+//! `DynamicImage` drops it, so it is read from the original bytes and written
+//! back into the encoded output afterwards.
+
+use std::io::Cursor;
+
+use exif::experimental::Writer;
+use exif::{Context, Exif, In, Reader, Tag};
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+use serde::{Deserialize, Serialize};
+
+use crate::color::convert_to_srgb;
+use crate::error::ConversionError;
+use crate::file_info::format_size;
+use crate::options::{ExifMode, IccMode, MetadataOptions};
+
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    pub exif: Option<Vec<u8>>, // TIFF structured, without the `Exif\0\0` header
+    pub icc: Option<Vec<u8>>,
+}
+
+// tags describing how a TIFF stores its pixels, which mean nothing once the
+// fields are moved into another file
+const TIFF_LAYOUT_TAGS: &[u16] = &[
+    256, 257, 258, 259, 262, 273, 277, 278, 279, 284, 317, 320, 322, 323, 324, 325, 338, 339, 530, 532,
+    tiff::tags::Tag::IccProfile.to_u16(), // handled on its own
+];
+
+impl Metadata {
+    /// Reads what metadata we support from a JPEG, TIFF, PNG or WebP file.
+    pub fn read(bytes: &[u8]) -> Metadata {
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(bytes))
+            .ok()
+            .and_then(|exif| rewrite_exif(&exif, |tag| tag.context() != Context::Tiff || !TIFF_LAYOUT_TAGS.contains(&tag.number())));
+        let icc = ImageReader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_decoder().ok())
+            .and_then(|mut decoder| decoder.icc_profile().ok().flatten());
+        Metadata { exif, icc }
+    }
+
+    /// Marks the image as upright, for after its pixels have been rotated.
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = &mut self.exif {
+            let _ = Orientation::remove_from_exif_chunk(exif);
+        }
+    }
+
+    /// e.g. `EXIF: 31 fields, with location; ICC profile: 3.1 KB`
+    pub fn describe(&self) -> Option<String> {
+        let exif = self.exif.clone().and_then(|exif| Reader::new().read_raw(exif).ok()).map(|exif| {
+            let location = if has_gps(&exif) { ", with location" } else { "" };
+            format!("EXIF: {} fields{location}", exif.fields().len())
+        });
+        let icc = self.icc.as_ref().map(|icc| format!("ICC profile: {}", format_size(icc.len())));
+
+        let parts: Vec<_> = [exif, icc].into_iter().flatten().collect();
+        (!parts.is_empty()).then(|| parts.join("; "))
+    }
+}
+
+fn has_gps(exif: &Exif) -> bool {
+    exif.fields().any(|field| field.tag.context() == Context::Gps)
+}
+
+/// Writes the primary image's fields that pass `keep` into a new EXIF block.
+/// The thumbnail is left out, it would no longer match the image.
+fn rewrite_exif(exif: &Exif, keep: impl Fn(Tag) -> bool) -> Option<Vec<u8>> {
+    let mut writer = Writer::new();
+    let mut kept = 0;
+    for field in exif.fields().filter(|field| field.ifd_num == In::PRIMARY && keep(field.tag)) {
+        writer.push_field(field);
+        kept += 1;
+    }
+    if kept == 0 {
+        return None;
+    }
+
+    let mut out = Cursor::new(Vec::new());
+    writer.write(&mut out, exif.little_endian()).ok()?;
+    Some(out.into_inner())
+}
+
+/// The EXIF block to write for `mode`, if any.
+pub fn exif_for_output(exif: &[u8], mode: ExifMode) -> Option<Vec<u8>> {
+    match mode {
+        ExifMode::Keep => Some(exif.to_vec()),
+        ExifMode::StripAll => None,
+        ExifMode::StripGps => {
+            let exif = Reader::new().read_raw(exif.to_vec()).ok()?;
+            if !has_gps(&exif) {
+                return Some(exif.buf().to_vec());
+            }
+            rewrite_exif(&exif, |tag| tag.context() != Context::Gps)
+        },
+    }
+}
+
+/// Whether EXIF is written into `format`. TIFF could hold it, but the tiff
+/// encoder we go through has no way to write the EXIF directory.
+pub fn supports_exif(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+}
+
+pub fn supports_icc(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Tiff | ImageFormat::WebP)
+}
+
+/// Converts the pixels to sRGB when asked to, before they are encoded.
+pub fn apply_color_profile(img: DynamicImage, metadata: &Metadata, options: &MetadataOptions) -> Result<DynamicImage, ConversionError> {
+    match (&metadata.icc, options.icc) {
+        (Some(icc), IccMode::ConvertToSrgb) => convert_to_srgb(img, icc),
+        _ => Ok(img),
+    }
+}
+
+/// The profile for `convert_image` to embed. Converted pixels are sRGB,
+/// which needs no profile.
+pub fn output_icc<'a>(metadata: &'a Metadata, options: &MetadataOptions) -> Option<&'a [u8]> {
+    match options.icc {
+        IccMode::Keep => metadata.icc.as_deref(),
+        IccMode::ConvertToSrgb => None,
+    }
+}
+
+/// Adds the kept metadata to an encoded file. Formats we can't write it
+/// into are returned unchanged.
+pub fn write_metadata(encoded: Vec<u8>, format: ImageFormat, metadata: &Metadata, options: &MetadataOptions) -> Vec<u8> {
+    let Some(exif) = metadata.exif.as_deref().and_then(|exif| exif_for_output(exif, options.exif)) else {
+        return encoded;
+    };
+
+    match format {
+        ImageFormat::Jpeg => embed_jpeg_exif(encoded, &exif),
+        ImageFormat::Png => embed_png_exif(encoded, &exif),
+        ImageFormat::WebP => embed_webp_exif(encoded, &exif),
+        _ => encoded,
+    }
+}
+
+// an APP1 segment right after SOI, or after the JFIF APP0 if there is one
+fn embed_jpeg_exif(mut jpeg: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    let payload = [b"Exif\0\0".as_slice(), exif].concat();
+    let Ok(length) = u16::try_from(payload.len() + 2) else {
+        return jpeg; // doesn't fit in a segment
+    };
+
+    let mut at = 2;
+    if jpeg.get(2..4) == Some(&[0xff, 0xe0]) {
+        at += 2 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+
+    let segment = [&[0xff, 0xe1], length.to_be_bytes().as_slice(), &payload].concat();
+    jpeg.splice(at..at, segment);
+    jpeg
+}
+
+// an eXIf chunk right after IHDR
+fn embed_png_exif(mut png: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    const AFTER_IHDR: usize = 8 + 25;
+    if png.len() < AFTER_IHDR {
+        return png;
+    }
+
+    png.splice(AFTER_IHDR..AFTER_IHDR, png_chunk(b"eXIf", exif));
+    png
+}
+
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    [&(data.len() as u32).to_be_bytes(), kind.as_slice(), data, &crc.finalize().to_be_bytes()].concat()
+}
+
+// EXIF needs the extended format, so a simple VP8L or VP8 file gets a VP8X header first
+fn embed_webp_exif(webp: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    if webp.len() < 30 || &webp[0..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        return webp;
+    }
+
+    let mut chunks = webp[12..].to_vec();
+    // canvas width and height minus one, and the alpha flag
+    let canvas = match &chunks[0..4] {
+        b"VP8X" => None,
+        b"VP8L" => {
+            // the VP8L header packs 14 bit width and height minus one and an alpha bit
+            let bits = u32::from_le_bytes([chunks[9], chunks[10], chunks[11], chunks[12]]);
+            let alpha = if (bits >> 28) & 1 == 1 { 0x10 } else { 0 };
+            Some((bits & 0x3fff, (bits >> 14) & 0x3fff, alpha))
+        },
+        b"VP8 " => {
+            // a key frame has its 14 bit width and height right after the start code
+            let side = |at: usize| u32::from(u16::from_le_bytes([chunks[at], chunks[at + 1]]) & 0x3fff);
+            Some((side(14) - 1, side(16) - 1, 0))
+        },
+        _ => return webp,
+    };
+
+    match canvas {
+        None => chunks[8] |= 0x08,
+        Some((width, height, alpha)) => {
+            let mut vp8x = vec![0x08 | alpha, 0, 0, 0];
+            vp8x.extend_from_slice(&width.to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&height.to_le_bytes()[..3]);
+            chunks.splice(0..0, webp_chunk(b"VP8X", &vp8x));
+        },
+    }
+    chunks.extend(webp_chunk(b"EXIF", exif));
+
+    [b"RIFF".as_slice(), &(chunks.len() as u32 + 4).to_le_bytes(), b"WEBP", &chunks].concat()
+}
+
+pub(crate) fn webp_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = [kind.as_slice(), &(data.len() as u32).to_le_bytes(), data].concat();
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{Field, Value};
+    use image::{RgbImage, RgbaImage};
+    use super::*;
+    use crate::convert::convert_image;
+    use crate::options::{EncodeOptions, WebpMode, WebpOptions};
+
+    fn exif_with_gps() -> Vec<u8> {
+        let fields = [
+            Field { tag: Tag::Artist, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"Someone".to_vec()]) },
+            Field { tag: Tag::GPSLatitudeRef, ifd_num: In::PRIMARY, value: Value::Ascii(vec![b"N".to_vec()]) },
+        ];
+        let mut writer = Writer::new();
+        fields.iter().for_each(|field| writer.push_field(field));
+        let mut out = Cursor::new(Vec::new());
+        writer.write(&mut out, false).unwrap();
+        out.into_inner()
+    }
+
+    fn read_back(encoded: &[u8]) -> Exif {
+        Reader::new().read_from_container(&mut Cursor::new(encoded)).unwrap()
+    }
+
+    #[test]
+    fn exif_survives_every_supported_format() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(9, 7, |x, _| image::Rgba([x as u8 * 20, 0, 0, 128])));
+        let metadata = Metadata { exif: Some(exif_with_gps()), ..Default::default() };
+        let keep = MetadataOptions { exif: ExifMode::Keep, ..Default::default() };
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP] {
+            let encoded = convert_image(img.clone(), format, &EncodeOptions::default(), None).unwrap();
+            let with_exif = write_metadata(encoded, format, &metadata, &keep);
+
+            assert!(read_back(&with_exif).get_field(Tag::Artist, In::PRIMARY).is_some(), "{format:?}");
+            let decoded = image::load_from_memory_with_format(&with_exif, format).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (9, 7), "{format:?}");
+        }
+
+        // an opaque lossy WebP starts out as a plain VP8 file
+        let lossy = EncodeOptions { webp: WebpOptions { mode: WebpMode::Lossy, quality: 80 }, ..Default::default() };
+        let encoded = convert_image(DynamicImage::ImageRgb8(RgbImage::new(9, 7)), ImageFormat::WebP, &lossy, None).unwrap();
+        let with_exif = write_metadata(encoded, ImageFormat::WebP, &metadata, &keep);
+        assert!(read_back(&with_exif).get_field(Tag::Artist, In::PRIMARY).is_some());
+        let decoded = image::load_from_memory_with_format(&with_exif, ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (9, 7));
+
+        // what MetadataSettings warns about
+        let tiff = convert_image(img, ImageFormat::Tiff, &EncodeOptions::default(), None).unwrap();
+        assert!(!supports_exif(ImageFormat::Tiff));
+        assert!(read_back(&write_metadata(tiff, ImageFormat::Tiff, &metadata, &keep)).get_field(Tag::Artist, In::PRIMARY).is_none());
+    }
+
+    fn display_p3() -> Vec<u8> {
+        moxcms::ColorProfile::new_display_p3().encode().unwrap()
+    }
+
+    #[test]
+    fn icc_profile_survives_every_supported_format() {
+        // image's TIFF decoder limits tags by the pixel buffer's size, too small an image loses the profile
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(128, 128, image::Rgb([200, 30, 30])));
+        let metadata = Metadata { icc: Some(display_p3()), ..Default::default() };
+
+        for format in [ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::Tiff, ImageFormat::WebP] {
+            let icc = output_icc(&metadata, &MetadataOptions::default());
+            let encoded = convert_image(img.clone(), format, &EncodeOptions::default(), icc).unwrap();
+            assert_eq!(Metadata::read(&encoded).icc, Some(display_p3()), "{format:?}");
+        }
+    }
+
+    #[test]
+    fn converting_to_srgb_changes_the_pixels_and_drops_the_profile() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0])));
+        let metadata = Metadata { icc: Some(display_p3()), ..Default::default() };
+        let options = MetadataOptions { icc: IccMode::ConvertToSrgb, ..Default::default() };
+
+        let converted = apply_color_profile(img, &metadata, &options).unwrap();
+        // P3 red is outside sRGB, so it clips to full red with the other channels at the floor
+        let [r, g, b] = converted.to_rgb8().get_pixel(0, 0).0;
+        assert!(r > 250 && g < 5 && b < 5, "{r} {g} {b}");
+        assert_eq!(output_icc(&metadata, &options), None);
+
+        // a darker P3 color is inside sRGB and has to move
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, image::Rgb([180, 120, 60])));
+        let converted = apply_color_profile(img, &metadata, &options).unwrap();
+        assert_ne!(converted.to_rgb8().get_pixel(0, 0).0, [180, 120, 60]);
+    }
+
+    #[test]
+    fn strip_gps_keeps_the_rest() {
+        let stripped = exif_for_output(&exif_with_gps(), ExifMode::StripGps).unwrap();
+        let exif = Reader::new().read_raw(stripped).unwrap();
+        assert!(exif.get_field(Tag::Artist, In::PRIMARY).is_some());
+        assert!(!has_gps(&exif));
+
+        assert_eq!(exif_for_output(&exif_with_gps(), ExifMode::StripAll), None);
+    }
+
+    #[test]
+    fn describes_the_exif() {
+        let metadata = Metadata { exif: Some(exif_with_gps()), ..Default::default() };
+        assert_eq!(metadata.describe().unwrap(), "EXIF: 2 fields, with location");
+        assert_eq!(Metadata::default().describe(), None);
+    }
+}
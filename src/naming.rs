@@ -0,0 +1,467 @@
+//! Output file names built from a user supplied template such as
+//! `{stem}_{width}x{height}.{ext}` or `{index:03}-{stem}-q{quality}.{ext}`.
+//!
+//! A variable can take a width after a colon. Numbers are zero padded to
+//! that width, text is cut to that many characters. `{{` and `}}` are
+//! literal braces.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::options::{EncodeOptions, PnmKind, SelectOption};
+
+pub const DEFAULT_TEMPLATE: &str = "{stem:64}.{ext}";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Variable {
+    Stem,
+    Format,
+    Ext,
+    Width,
+    Height,
+    Index,
+    Date,
+    Hash,
+    Quality,
+}
+
+impl Variable {
+    const ALL: &'static [Variable] = &[
+        Variable::Stem, Variable::Format, Variable::Ext, Variable::Width, Variable::Height,
+        Variable::Index, Variable::Date, Variable::Hash, Variable::Quality,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Variable::Stem => "stem",
+            Variable::Format => "format",
+            Variable::Ext => "ext",
+            Variable::Width => "width",
+            Variable::Height => "height",
+            Variable::Index => "index",
+            Variable::Date => "date",
+            Variable::Hash => "hash",
+            Variable::Quality => "quality",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Variable> {
+        Self::ALL.iter().copied().find(|variable| variable.name() == name)
+    }
+
+    /// Names of every variable, for hints in the UI.
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        Self::ALL.iter().map(Variable::name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Segment {
+    Text(String),
+    Variable { variable: Variable, width: Option<usize> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TemplateError {
+    Empty,
+    UnclosedBrace(usize),
+    UnmatchedBrace(usize),
+    UnknownVariable(String),
+    InvalidWidth(String),
+    PathSeparator,
+    NoName,
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Empty => write!(f, "Template is empty"),
+            TemplateError::UnclosedBrace(pos) => write!(f, "Unclosed '{{' at position {pos}"),
+            TemplateError::UnmatchedBrace(pos) => write!(f, "Unmatched '}}' at position {pos}, use '}}}}' for a literal brace"),
+            TemplateError::UnknownVariable(name) => write!(f, "Unknown variable '{name}', expected one of {}", Variable::names().collect::<Vec<_>>().join(", ")),
+            TemplateError::InvalidWidth(width) => write!(f, "Invalid width '{width}'"),
+            TemplateError::PathSeparator => write!(f, "Template can't contain '/' or '\\'"),
+            TemplateError::NoName => write!(f, "Template needs a variable or text other than dots, or some names would be empty, '.' or '..'"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Everything a template can refer to for one output file.
+pub struct NameFields<'a> {
+    pub stem: &'a str,
+    pub format: ImageFormat,
+    pub extension: &'a str,
+    pub width: u32,
+    pub height: u32,
+    pub index: usize,
+    pub date: &'a str,
+    pub data: &'a [u8],
+    pub quality: Option<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NameTemplate {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate::parse(DEFAULT_TEMPLATE).expect("default template is valid")
+    }
+}
+
+impl NameTemplate {
+    pub fn parse(source: &str) -> Result<NameTemplate, TemplateError> {
+        if source.trim().is_empty() {
+            return Err(TemplateError::Empty);
+        }
+
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+
+        while let Some((pos, c)) = chars.next() {
+            match c {
+                '{' if chars.next_if(|&(_, next)| next == '{').is_some() => text.push('{'),
+                '}' if chars.next_if(|&(_, next)| next == '}').is_some() => text.push('}'),
+                '}' => return Err(TemplateError::UnmatchedBrace(pos)),
+                '/' | '\\' => return Err(TemplateError::PathSeparator),
+                '{' => {
+                    let mut inner = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '}')) => break,
+                            Some((_, c)) => inner.push(c),
+                            None => return Err(TemplateError::UnclosedBrace(pos)),
+                        }
+                    }
+
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    segments.push(parse_variable(&inner)?);
+                },
+                c => text.push(c),
+            }
+        }
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+        if !segments.iter().any(Segment::always_named) {
+            return Err(TemplateError::NoName);
+        }
+
+        Ok(NameTemplate { source: source.to_string(), segments })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn render(&self, fields: &NameFields) -> String {
+        let name: String = self.segments.iter().map(|segment| match segment {
+            Segment::Text(text) => text.clone(),
+            Segment::Variable { variable, width } => render_variable(*variable, *width, fields),
+        }).collect();
+
+        // archives can't hold an entry named '', '.' or '..', which a cut or
+        // missing variable can still leave
+        if name.chars().all(|c| c == '.') {
+            return "_".to_string();
+        }
+        name
+    }
+}
+
+impl Segment {
+    // without one of these every name would come out as dots, which render replaces
+    fn always_named(&self) -> bool {
+        match self {
+            Segment::Text(text) => text.chars().any(|c| c != '.'),
+            Segment::Variable { variable, .. } => *variable != Variable::Quality,
+        }
+    }
+}
+
+fn parse_variable(inner: &str) -> Result<Segment, TemplateError> {
+    let (name, width) = match inner.split_once(':') {
+        Some((name, width)) => (name, Some(width)),
+        None => (inner, None),
+    };
+
+    let variable = Variable::from_name(name.trim())
+        .ok_or_else(|| TemplateError::UnknownVariable(name.to_string()))?;
+    let width = width
+        .map(|width| match width.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(TemplateError::InvalidWidth(width.to_string())),
+        })
+        .transpose()?;
+
+    Ok(Segment::Variable { variable, width })
+}
+
+fn render_variable(variable: Variable, width: Option<usize>, fields: &NameFields) -> String {
+    let number = |n: u64| match width {
+        Some(width) => format!("{n:0width$}"),
+        None => n.to_string(),
+    };
+    let text = |s: &str| match width {
+        Some(width) => s.chars().take(width).collect(),
+        None => s.to_string(),
+    };
+
+    match variable {
+        Variable::Stem => text(&sanitize(fields.stem)),
+        Variable::Format => text(&format!("{:?}", fields.format).to_lowercase()),
+        Variable::Ext => text(fields.extension),
+        Variable::Width => number(fields.width as u64),
+        Variable::Height => number(fields.height as u64),
+        Variable::Index => number(fields.index as u64),
+        Variable::Date => text(fields.date),
+        Variable::Hash => text(&format!("{:016x}", content_hash(fields.data))),
+        Variable::Quality => fields.quality.map(|q| number(q as u64)).unwrap_or_default(),
+    }
+}
+
+// original names come from the user's file system and could still hold separators
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if matches!(c, '/' | '\\') || c.is_control() { '_' } else { c }).collect()
+}
+
+/// The extension used unless the user picked another one of
+/// `format.extensions_str()`. PNM files are named after their subtype.
+pub fn default_extension(format: ImageFormat, options: &EncodeOptions) -> &'static str {
+    match format {
+        ImageFormat::Pnm => match options.pnm.subtype {
+            PnmKind::Bitmap => "pbm",
+            PnmKind::Graymap => "pgm",
+            PnmKind::Pixmap => "ppm",
+            PnmKind::ArbitraryMap => "pam",
+        },
+        format => format.extensions_str()[0],
+    }
+}
+
+/// 64-bit FNV-1a, plenty to tell files apart by name.
+pub fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Splits `dir/sub/file.png` into `("dir/sub", "file.png")`.
+pub fn split_relative_path(path: &str) -> (&str, &str) {
+    path.trim_start_matches('/').rsplit_once('/').unwrap_or(("", path.trim_start_matches('/')))
+}
+
+/// The file name without its last extension.
+pub fn file_stem(name: &str) -> &str {
+    match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    }
+}
+
+/// What to do when two outputs end up with the same name. Names are
+/// compared ignoring case, since Windows and macOS would still overwrite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CollisionPolicy {
+    #[default]
+    NumericSuffix,
+    HashSuffix,
+    Skip,
+}
+
+impl SelectOption for CollisionPolicy {
+    const ALL: &'static [Self] = &[Self::NumericSuffix, Self::HashSuffix, Self::Skip];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::NumericSuffix => "Add number",
+            Self::HashSuffix => "Add content hash",
+            Self::Skip => "Skip",
+        }
+    }
+}
+
+/// The final name for one output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameOutcome {
+    Unique(String),
+    Renamed { original: String, name: String },
+    Skipped(String),
+}
+
+impl NameOutcome {
+    /// The name to write the file under, `None` if it is left out.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            NameOutcome::Unique(name) | NameOutcome::Renamed { name, .. } => Some(name),
+            NameOutcome::Skipped(_) => None,
+        }
+    }
+
+    /// A line for the report shown after the download, `None` if nothing changed.
+    pub fn describe(&self) -> Option<String> {
+        match self {
+            NameOutcome::Unique(_) => None,
+            NameOutcome::Renamed { original, name } => Some(format!("{original} renamed to {name}")),
+            NameOutcome::Skipped(name) => Some(format!("{name} skipped, the name was already used")),
+        }
+    }
+}
+
+/// Gives every `(name, data)` a name no earlier entry has, keeping the
+/// first occurrence of a name as it is.
+pub fn resolve_collisions(files: &[(String, &[u8])], policy: CollisionPolicy) -> Vec<NameOutcome> {
+    let mut taken = HashSet::new();
+
+    files.iter().map(|(name, data)| {
+        if taken.insert(name.to_lowercase()) {
+            return NameOutcome::Unique(name.clone());
+        }
+
+        // the suffix goes on the file name, not on a dotted folder
+        let (dir, file) = split_relative_path(name);
+        let dir = if dir.is_empty() { String::new() } else { format!("{dir}/") };
+        let stem = file_stem(file);
+        let ext = &file[stem.len()..];
+
+        let renamed = match policy {
+            CollisionPolicy::Skip => return NameOutcome::Skipped(name.clone()),
+            CollisionPolicy::NumericSuffix => format!("{dir}{stem}"),
+            CollisionPolicy::HashSuffix => format!("{dir}{stem}_{:08x}", content_hash(data) >> 32),
+        };
+
+        // a hash suffix can still collide when the same image is in there twice
+        let name_with = |n: usize| if n < 2 { format!("{renamed}{ext}") } else { format!("{renamed}_{n}{ext}") };
+        let first = if policy == CollisionPolicy::NumericSuffix { 2 } else { 1 };
+        let new_name = (first..)
+            .map(name_with)
+            .find(|candidate| taken.insert(candidate.to_lowercase()))
+            .expect("an unused suffix exists");
+
+        NameOutcome::Renamed { original: name.clone(), name: new_name }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> NameFields<'static> {
+        NameFields {
+            stem: "holiday photo",
+            format: ImageFormat::Jpeg,
+            extension: "jpeg",
+            width: 640,
+            height: 480,
+            index: 7,
+            date: "2024-08-20",
+            data: b"encoded",
+            quality: Some(85),
+        }
+    }
+
+    fn render(template: &str) -> String {
+        NameTemplate::parse(template).unwrap().render(&fields())
+    }
+
+    #[test]
+    fn renders_variables() {
+        assert_eq!(render("{stem}_{width}x{height}.{ext}"), "holiday photo_640x480.jpeg");
+        assert_eq!(render("{index:03}-{stem}-q{quality}.{ext}"), "007-holiday photo-q85.jpeg");
+        assert_eq!(render("{date}_{format}_{hash:8}"), format!("2024-08-20_jpeg_{:08x}", content_hash(b"encoded") >> 32));
+        assert_eq!(render("{stem:7}.{ext}"), "holiday.jpeg");
+        assert_eq!(render("{{{stem}}}"), "{holiday photo}");
+    }
+
+    #[test]
+    fn names_of_only_dots_are_replaced() {
+        let render_stem = |template: &str, stem| NameTemplate::parse(template).unwrap().render(&NameFields { stem, ..fields() });
+        assert_eq!(render_stem("{stem}", ".."), "_");
+        assert_eq!(render_stem("{stem}", ""), "_");
+        assert_eq!(render_stem("{stem:1}", ".hidden"), "_");
+        assert_eq!(render_stem("{stem:2}", "..x"), "_");
+        assert_eq!(render_stem("{stem:2}.{ext}", "..x"), "...jpeg");
+        assert_eq!(render_stem("{stem:2}", ".hidden"), ".h");
+    }
+
+    #[test]
+    fn default_template_keeps_the_old_names() {
+        let name = NameTemplate::default().render(&NameFields { stem: &"x".repeat(100), ..fields() });
+        assert_eq!(name, format!("{}.jpeg", "x".repeat(64)));
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert_eq!(NameTemplate::parse(" "), Err(TemplateError::Empty));
+        assert_eq!(NameTemplate::parse("a{stem"), Err(TemplateError::UnclosedBrace(1)));
+        assert_eq!(NameTemplate::parse("a}"), Err(TemplateError::UnmatchedBrace(1)));
+        assert_eq!(NameTemplate::parse("{size}"), Err(TemplateError::UnknownVariable("size".to_string())));
+        assert_eq!(NameTemplate::parse("{index:x}"), Err(TemplateError::InvalidWidth("x".to_string())));
+        assert_eq!(NameTemplate::parse("out/{stem}"), Err(TemplateError::PathSeparator));
+        assert_eq!(NameTemplate::parse(".."), Err(TemplateError::NoName));
+        assert_eq!(NameTemplate::parse(".{quality}"), Err(TemplateError::NoName));
+        assert!(NameTemplate::parse("{stem}..{ext}").is_ok());
+    }
+
+    #[test]
+    fn stem_strips_the_last_extension() {
+        assert_eq!(file_stem("photo.final.png"), "photo.final");
+        assert_eq!(file_stem(".hidden"), ".hidden");
+        assert_eq!(file_stem("noext"), "noext");
+    }
+
+    fn files(names: &[&str]) -> Vec<(String, &'static [u8])> {
+        names.iter().map(|name| (name.to_string(), b"same".as_slice())).collect()
+    }
+
+    #[test]
+    fn numeric_suffix_renames_duplicates() {
+        let outcomes = resolve_collisions(&files(&["photo.webp", "Photo.webp", "photo.webp", "photo_2.webp"]), CollisionPolicy::NumericSuffix);
+        let names: Vec<_> = outcomes.iter().map(|outcome| outcome.name().unwrap()).collect();
+        assert_eq!(names, ["photo.webp", "Photo_2.webp", "photo_3.webp", "photo_2_2.webp"]);
+        assert_eq!(outcomes[0], NameOutcome::Unique("photo.webp".to_string()));
+        assert_eq!(outcomes[2].describe().unwrap(), "photo.webp renamed to photo_3.webp");
+    }
+
+    #[test]
+    fn suffix_goes_on_the_file_name() {
+        let outcomes = resolve_collisions(&files(&["v1.2/photo", "v1.2/photo", "v1.2/a.png", "v1.2/a.png"]), CollisionPolicy::NumericSuffix);
+        assert_eq!(outcomes[1].name(), Some("v1.2/photo_2"));
+        assert_eq!(outcomes[3].name(), Some("v1.2/a_2.png"));
+    }
+
+    #[test]
+    fn splits_relative_paths() {
+        assert_eq!(split_relative_path("assets/icons/a.png"), ("assets/icons", "a.png"));
+        assert_eq!(split_relative_path("/a.png"), ("", "a.png"));
+        assert_eq!(split_relative_path("a.png"), ("", "a.png"));
+    }
+
+    #[test]
+    fn hash_suffix_uses_the_content() {
+        let files = vec![("a.png".to_string(), b"one".as_slice()), ("a.png".to_string(), b"two".as_slice()), ("a.png".to_string(), b"two".as_slice())];
+        let outcomes = resolve_collisions(&files, CollisionPolicy::HashSuffix);
+        let hash = format!("{:08x}", content_hash(b"two") >> 32);
+        assert_eq!(outcomes[1].name().unwrap(), format!("a_{hash}.png"));
+        assert_eq!(outcomes[2].name().unwrap(), format!("a_{hash}_2.png"));
+    }
+
+    #[test]
+    fn skip_leaves_duplicates_out() {
+        let outcomes = resolve_collisions(&files(&["x", "x", "y"]), CollisionPolicy::Skip);
+        assert_eq!(outcomes, [
+            NameOutcome::Unique("x".to_string()),
+            NameOutcome::Skipped("x".to_string()),
+            NameOutcome::Unique("y".to_string()),
+        ]);
+    }
+}
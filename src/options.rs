@@ -0,0 +1,557 @@
+use image::codecs::png::{CompressionType, FilterType};
+use image::codecs::pnm::{PnmSubtype, SampleEncoding};
+use image::ImageFormat;
+use jpeg_encoder::SamplingFactor;
+use serde::{Deserialize, Serialize};
+
+/// A setting that can be picked from a fixed list in the options panel.
+pub trait SelectOption: Copy + PartialEq + 'static {
+    const ALL: &'static [Self];
+
+    fn label(&self) -> &'static str;
+
+    fn from_label(label: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|option| option.label() == label)
+    }
+}
+
+/// Encoder settings for every output format. Only the entry matching the
+/// target format is read by `convert_image`, the rest are kept so switching
+/// formats in the panel doesn't reset them.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)] // presets saved before an option existed still load
+pub struct EncodeOptions {
+    pub jpeg: JpegOptions,
+    pub png: PngOptions,
+    pub avif: AvifOptions,
+    pub tiff: TiffOptions,
+    pub pnm: PnmOptions,
+    pub webp: WebpOptions,
+    pub bmp: BmpOptions,
+    pub alpha: AlphaOptions,
+    pub metadata: MetadataOptions,
+}
+
+impl EncodeOptions {
+    /// The quality setting used for `format`, if its encoder has one.
+    pub fn quality(&self, format: ImageFormat) -> Option<u8> {
+        match format {
+            ImageFormat::Jpeg => Some(self.jpeg.quality),
+            ImageFormat::Avif => Some(self.avif.quality),
+            ImageFormat::WebP if self.webp.mode == WebpMode::Lossy => Some(self.webp.quality),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JpegOptions {
+    pub quality: u8, // 1-100
+    pub subsampling: ChromaSubsampling,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        JpegOptions {
+            quality: 75,
+            subsampling: ChromaSubsampling::Yuv420,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    Yuv444,
+    Yuv422,
+    #[default]
+    Yuv420,
+}
+
+impl SelectOption for ChromaSubsampling {
+    const ALL: &'static [Self] = &[Self::Yuv444, Self::Yuv422, Self::Yuv420];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Yuv444 => "4:4:4",
+            Self::Yuv422 => "4:2:2",
+            Self::Yuv420 => "4:2:0",
+        }
+    }
+}
+
+impl From<ChromaSubsampling> for SamplingFactor {
+    fn from(value: ChromaSubsampling) -> Self {
+        match value {
+            ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct PngOptions {
+    pub compression: PngCompression,
+    pub filter: PngFilter,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+}
+
+impl SelectOption for PngCompression {
+    const ALL: &'static [Self] = &[Self::Fast, Self::Default, Self::Best];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Fast => "Fast",
+            Self::Default => "Default",
+            Self::Best => "Best",
+        }
+    }
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(value: PngCompression) -> Self {
+        match value {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PngFilter {
+    NoFilter,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    #[default]
+    Adaptive,
+}
+
+impl SelectOption for PngFilter {
+    const ALL: &'static [Self] = &[
+        Self::NoFilter, Self::Sub, Self::Up, Self::Avg, Self::Paeth, Self::Adaptive,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::NoFilter => "None",
+            Self::Sub => "Sub",
+            Self::Up => "Up",
+            Self::Avg => "Average",
+            Self::Paeth => "Paeth",
+            Self::Adaptive => "Adaptive",
+        }
+    }
+}
+
+impl From<PngFilter> for FilterType {
+    fn from(value: PngFilter) -> Self {
+        match value {
+            PngFilter::NoFilter => FilterType::NoFilter,
+            PngFilter::Sub => FilterType::Sub,
+            PngFilter::Up => FilterType::Up,
+            PngFilter::Avg => FilterType::Avg,
+            PngFilter::Paeth => FilterType::Paeth,
+            PngFilter::Adaptive => FilterType::Adaptive,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AvifOptions {
+    pub speed: u8,   // 1 (slowest) - 10 (fastest)
+    pub quality: u8, // 1-100
+}
+
+impl Default for AvifOptions {
+    fn default() -> Self {
+        // same defaults as `AvifEncoder::new`
+        AvifOptions { speed: 4, quality: 80 }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct TiffOptions {
+    pub compression: TiffCompression,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TiffCompression {
+    #[default]
+    Uncompressed,
+    Lzw,
+    Deflate,
+    PackBits,
+}
+
+impl SelectOption for TiffCompression {
+    const ALL: &'static [Self] = &[Self::Uncompressed, Self::Lzw, Self::Deflate, Self::PackBits];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Uncompressed => "None",
+            Self::Lzw => "LZW",
+            Self::Deflate => "Deflate",
+            Self::PackBits => "PackBits",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct PnmOptions {
+    pub subtype: PnmKind,
+    pub encoding: PnmEncoding,
+}
+
+impl From<PnmOptions> for PnmSubtype {
+    fn from(value: PnmOptions) -> Self {
+        let encoding = match value.encoding {
+            PnmEncoding::Binary => SampleEncoding::Binary,
+            PnmEncoding::Ascii => SampleEncoding::Ascii,
+        };
+
+        match value.subtype {
+            PnmKind::Bitmap => PnmSubtype::Bitmap(encoding),
+            PnmKind::Graymap => PnmSubtype::Graymap(encoding),
+            PnmKind::Pixmap => PnmSubtype::Pixmap(encoding),
+            PnmKind::ArbitraryMap => PnmSubtype::ArbitraryMap, // PAM is always binary
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PnmKind {
+    Bitmap,
+    Graymap,
+    #[default]
+    Pixmap,
+    ArbitraryMap,
+}
+
+impl SelectOption for PnmKind {
+    const ALL: &'static [Self] = &[Self::Bitmap, Self::Graymap, Self::Pixmap, Self::ArbitraryMap];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Bitmap => "PBM",
+            Self::Graymap => "PGM",
+            Self::Pixmap => "PPM",
+            Self::ArbitraryMap => "PAM",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PnmEncoding {
+    #[default]
+    Binary,
+    Ascii,
+}
+
+impl SelectOption for PnmEncoding {
+    const ALL: &'static [Self] = &[Self::Binary, Self::Ascii];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Binary => "Binary",
+            Self::Ascii => "ASCII",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WebpOptions {
+    pub mode: WebpMode,
+    pub quality: u8, // 1-100, only used by lossy mode
+}
+
+impl Default for WebpOptions {
+    fn default() -> Self {
+        WebpOptions {
+            mode: WebpMode::Lossless,
+            quality: 80,
+        }
+    }
+}
+
+/// Lossless writes VP8L through the `image` crate, lossy writes a VP8 frame
+/// with our own encoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum WebpMode {
+    #[default]
+    Lossless,
+    #[serde(alias = "ReducedColors")] // what lossy was briefly called
+    Lossy,
+}
+
+impl SelectOption for WebpMode {
+    const ALL: &'static [Self] = &[Self::Lossless, Self::Lossy];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Lossless => "Lossless",
+            Self::Lossy => "Lossy",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct BmpOptions {
+    pub depth: BmpDepth,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum BmpDepth {
+    Bits24,
+    #[default]
+    Bits32,
+}
+
+impl SelectOption for BmpDepth {
+    const ALL: &'static [Self] = &[Self::Bits24, Self::Bits32];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Bits24 => "24-bit",
+            Self::Bits32 => "32-bit (alpha)",
+        }
+    }
+}
+
+/// What to do with transparent pixels when the target can't store alpha.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AlphaOptions {
+    pub mode: AlphaMode,
+    pub background: [u8; 3], // only used by `AlphaMode::Background`
+}
+
+impl Default for AlphaOptions {
+    fn default() -> Self {
+        AlphaOptions {
+            mode: AlphaMode::Background,
+            background: [255, 255, 255],
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AlphaMode {
+    #[default]
+    Background,
+    Checkerboard,
+    Fail,
+}
+
+impl SelectOption for AlphaMode {
+    const ALL: &'static [Self] = &[Self::Background, Self::Checkerboard, Self::Fail];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Background => "Background color",
+            Self::Checkerboard => "Checkerboard",
+            Self::Fail => "Fail",
+        }
+    }
+}
+
+/// What happens to the metadata read from the upload.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct MetadataOptions {
+    pub exif: ExifMode,
+    pub icc: IccMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ExifMode {
+    Keep,
+    StripGps,
+    #[default]
+    StripAll,
+}
+
+impl SelectOption for ExifMode {
+    const ALL: &'static [Self] = &[Self::Keep, Self::StripGps, Self::StripAll];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Keep => "Keep",
+            Self::StripGps => "Strip location",
+            Self::StripAll => "Strip all",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum IccMode {
+    #[default]
+    Keep,
+    ConvertToSrgb,
+}
+
+impl SelectOption for IccMode {
+    const ALL: &'static [Self] = &[Self::Keep, Self::ConvertToSrgb];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Keep => "Keep",
+            Self::ConvertToSrgb => "Convert to sRGB",
+        }
+    }
+}
+
+/// A crop in pixels of the image it was drawn on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CropBox {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Shapes the crop editor can hold the box to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CropAspect {
+    #[default]
+    Free,
+    Square,
+    Widescreen,
+    Portrait,
+}
+
+impl CropAspect {
+    /// Width to height, `None` for a free crop.
+    pub fn ratio(&self) -> Option<f64> {
+        match self {
+            Self::Free => None,
+            Self::Square => Some(1.0),
+            Self::Widescreen => Some(16.0 / 9.0),
+            Self::Portrait => Some(4.0 / 5.0),
+        }
+    }
+}
+
+impl SelectOption for CropAspect {
+    const ALL: &'static [Self] = &[Self::Free, Self::Square, Self::Widescreen, Self::Portrait];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Free => "Free",
+            Self::Square => "1:1",
+            Self::Widescreen => "16:9",
+            Self::Portrait => "4:5",
+        }
+    }
+}
+
+/// Settings of a resize step. Each mode reads only the fields it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ResizeOptions {
+    pub mode: ResizeMode,
+    pub width: u32,  // Exact, Fit and Fill
+    pub height: u32, // Exact, Fit and Fill
+    pub percent: u32,
+    pub longest_edge: u32,
+    pub filter: ResizeFilter,
+    pub never_upscale: bool,
+}
+
+impl ResizeOptions {
+    pub const DEFAULT: ResizeOptions = ResizeOptions {
+        mode: ResizeMode::Fit,
+        width: 1920,
+        height: 1080,
+        percent: 50,
+        longest_edge: 2048,
+        filter: ResizeFilter::Lanczos3,
+        never_upscale: true,
+    };
+}
+
+impl Default for ResizeOptions {
+    fn default() -> Self {
+        ResizeOptions::DEFAULT
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ResizeMode {
+    Exact,
+    #[default]
+    Fit,
+    Fill,
+    Percent,
+    LongestEdge,
+}
+
+impl SelectOption for ResizeMode {
+    const ALL: &'static [Self] = &[Self::Exact, Self::Fit, Self::Fill, Self::Percent, Self::LongestEdge];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Exact => "Exact size",
+            Self::Fit => "Fit within",
+            Self::Fill => "Fill and crop",
+            Self::Percent => "Scale by percent",
+            Self::LongestEdge => "Limit longest edge",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl SelectOption for ResizeFilter {
+    const ALL: &'static [Self] = &[Self::Nearest, Self::Triangle, Self::CatmullRom, Self::Gaussian, Self::Lanczos3];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Nearest => "Nearest",
+            Self::Triangle => "Triangle",
+            Self::CatmullRom => "Catmull-Rom",
+            Self::Gaussian => "Gaussian",
+            Self::Lanczos3 => "Lanczos3",
+        }
+    }
+}
+
+impl From<ResizeFilter> for image::imageops::FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Nearest => Self::Nearest,
+            ResizeFilter::Triangle => Self::Triangle,
+            ResizeFilter::CatmullRom => Self::CatmullRom,
+            ResizeFilter::Gaussian => Self::Gaussian,
+            ResizeFilter::Lanczos3 => Self::Lanczos3,
+        }
+    }
+}
+
+/// Formats a color the way `<input type="color">` expects it, e.g. `#ff8000`.
+pub fn to_hex_color([r, g, b]: [u8; 3]) -> String {
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
+
+pub fn parse_hex_color(hex: &str) -> Option<[u8; 3]> {
+    let hex = hex.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
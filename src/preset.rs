@@ -0,0 +1,156 @@
+//! Named presets: a target format, its encoder options and a recipe. They are
+//! saved as JSON files, or packed into the page URL so a link opens the
+//! converter set up the same way.
+
+use std::fmt::{Display, Formatter};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::options::EncodeOptions;
+use crate::recipe::Recipe;
+use crate::serde_image;
+
+/// Written into every preset. Raise it when a preset means something different
+/// than before, older versions then refuse the preset instead of misreading it.
+pub const PRESET_VERSION: u32 = 1;
+
+const FRAGMENT_KEY: &str = "preset=";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub version: u32,
+    pub name: String,
+    #[serde(with = "serde_image::image_format")]
+    pub format: ImageFormat,
+    #[serde(default)]
+    pub options: EncodeOptions,
+    #[serde(default)]
+    pub recipe: Recipe,
+}
+
+/// Why a preset couldn't be loaded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PresetError {
+    Invalid(String),
+    NewerVersion(u32),
+    NotInFragment,
+}
+
+impl Display for PresetError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PresetError::Invalid(msg) => write!(f, "Not a valid preset: {msg}"),
+            PresetError::NewerVersion(version) =>
+                write!(f, "The preset is version {version}, this converter reads up to version {PRESET_VERSION}"),
+            PresetError::NotInFragment => write!(f, "The link has no preset in it"),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl From<serde_json::Error> for PresetError {
+    fn from(err: serde_json::Error) -> Self {
+        PresetError::Invalid(err.to_string())
+    }
+}
+
+// read first, so a newer preset is reported as such rather than as invalid
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+impl Preset {
+    pub fn new(name: String, format: ImageFormat, options: EncodeOptions, recipe: Recipe) -> Preset {
+        Preset { version: PRESET_VERSION, name, format, options, recipe }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("presets always serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Preset, PresetError> {
+        let Version { version } = serde_json::from_str(json)?;
+        if version > PRESET_VERSION {
+            return Err(PresetError::NewerVersion(version));
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// `preset=` and the preset as URL safe base64, to put after the `#` of a link.
+    pub fn to_fragment(&self) -> String {
+        let json = serde_json::to_string(self).expect("presets always serialize");
+        format!("{FRAGMENT_KEY}{}", URL_SAFE_NO_PAD.encode(json))
+    }
+
+    /// Reads a fragment made by `to_fragment`, with or without its leading `#`.
+    pub fn from_fragment(fragment: &str) -> Result<Preset, PresetError> {
+        let encoded = fragment.trim_start_matches('#')
+            .strip_prefix(FRAGMENT_KEY)
+            .ok_or(PresetError::NotInFragment)?;
+        let json = URL_SAFE_NO_PAD.decode(encoded)
+            .map_err(|err| PresetError::Invalid(err.to_string()))?;
+        let json = String::from_utf8(json)
+            .map_err(|err| PresetError::Invalid(err.to_string()))?;
+        Preset::from_json(&json)
+    }
+
+    /// The name as a file name, e.g. `web-hero-1920-webp-q80.json`.
+    pub fn file_name(&self) -> String {
+        let stem = self.name
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join("-");
+        if stem.is_empty() { "preset.json".to_string() } else { format!("{stem}.json") }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{ResizeMode, ResizeOptions, WebpMode};
+    use crate::recipe::{Operation, OperationKind};
+
+    fn preset() -> Preset {
+        let mut options = EncodeOptions::default();
+        options.webp.quality = 80;
+        let recipe = Recipe {
+            operations: vec![Operation::new(OperationKind::Resize(ResizeOptions { mode: ResizeMode::Fit, ..Default::default() }))],
+        };
+        Preset::new("Web hero 1920 WebP q80".to_string(), ImageFormat::WebP, options, recipe)
+    }
+
+    #[test]
+    fn presets_round_trip_through_json_and_links() {
+        assert_eq!(Preset::from_json(&preset().to_json()), Ok(preset()));
+        assert_eq!(Preset::from_fragment(&format!("#{}", preset().to_fragment())), Ok(preset()));
+        assert_eq!(Preset::from_fragment("#other=1"), Err(PresetError::NotInFragment));
+        assert_eq!(preset().file_name(), "web-hero-1920-webp-q80.json");
+    }
+
+    #[test]
+    fn missing_settings_take_their_defaults() {
+        let preset = Preset::from_json(r#"{"version": 1, "name": "Plain", "format": "png", "options": {}}"#).unwrap();
+        assert_eq!(preset.options, EncodeOptions::default());
+        assert_eq!(preset.recipe, Recipe::default());
+    }
+
+    #[test]
+    fn renamed_options_still_load() {
+        let preset = Preset::from_json(r#"{"version": 1, "name": "Old", "format": "webp", "options": {"webp": {"mode": "ReducedColors", "quality": 70}}}"#).unwrap();
+        assert_eq!(preset.options.webp.mode, WebpMode::Lossy);
+    }
+
+    #[test]
+    fn newer_presets_are_refused() {
+        let json = r#"{"version": 2, "name": "From the future", "format": "avif", "steps": []}"#;
+        assert_eq!(Preset::from_json(json), Err(PresetError::NewerVersion(2)));
+        assert!(matches!(Preset::from_json("{}"), Err(PresetError::Invalid(_))));
+    }
+}
@@ -0,0 +1,102 @@
+//! Saving the panel's settings as a named preset: a JSON file to export and
+//! import, or a link that opens the converter with them. The settings are
+//! also kept in the session store, so a reload starts from them.
+
+use image::ImageFormat;
+use js_sys::Uint8Array;
+use leptos::{component, create_effect, create_signal, event_target, event_target_value, view, Callable, Callback, IntoView, ReadSignal, RwSignal, SignalGet, SignalSet};
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_sys::{Event, HtmlInputElement};
+use web_image_converter::options::EncodeOptions;
+use web_image_converter::preset::{Preset, PresetError};
+use web_image_converter::recipe::Recipe;
+use web_image_converter::session::SETTINGS_KEY;
+
+use crate::js::downloadFile;
+use crate::session_store::{read_record, write_record};
+
+#[component]
+pub fn PresetBar(
+    name: RwSignal<String>,
+    format: ReadSignal<ImageFormat>,
+    options: RwSignal<EncodeOptions>,
+    recipe: RwSignal<Recipe>,
+    #[prop(into)] on_load: Callback<Preset>,
+) -> impl IntoView {
+    let (message, set_message) = create_signal(None);
+    let (restored, set_restored) = create_signal(false);
+    let current = move || Preset::new(name.get(), format.get(), options.get(), recipe.get());
+
+    // a link made with "Copy link" opens with its preset, otherwise the last session's settings are back
+    let window = web_sys::window().expect("no window");
+    match window.location().hash().map(|hash| Preset::from_fragment(&hash)) {
+        Ok(Ok(preset)) => {
+            on_load.call(preset);
+            set_restored.set(true);
+        },
+        Ok(Err(PresetError::NotInFragment)) | Err(_) => spawn_local(async move {
+            let stored = read_record(SETTINGS_KEY).await.and_then(|json| Preset::from_json(&String::from_utf8(json).ok()?).ok());
+            if let Some(preset) = stored {
+                on_load.call(preset);
+            }
+            set_restored.set(true);
+        }),
+        Ok(Err(err)) => {
+            set_message.set(Some(err.to_string()));
+            set_restored.set(true);
+        },
+    }
+    create_effect(move |_| {
+        let json = current().to_json();
+        if restored.get() {
+            write_record(SETTINGS_KEY, json.as_bytes());
+        }
+    });
+
+    let export = move |_| {
+        let preset = current();
+        downloadFile(&preset.file_name(), Uint8Array::from(preset.to_json().as_bytes()));
+    };
+    let share = move |_| {
+        let window = web_sys::window().expect("no window");
+        let location = window.location();
+        let _ = location.set_hash(&current().to_fragment());
+        if let Ok(link) = location.href() {
+            let _ = window.navigator().clipboard().write_text(&link);
+            set_message.set(Some("Link copied".to_string()));
+        }
+    };
+    let import = move |ev: Event| {
+        let input = event_target::<HtmlInputElement>(&ev);
+        let Some(file) = input.files().and_then(|files| files.get(0)) else {
+            return;
+        };
+        input.set_value(""); // so picking the same file again loads it again
+        spawn_local(async move {
+            let json = JsFuture::from(file.text()).await.ok().and_then(|text| text.as_string()).unwrap_or_default();
+            match Preset::from_json(&json) {
+                Ok(preset) => {
+                    set_message.set(None);
+                    on_load.call(preset);
+                },
+                Err(err) => set_message.set(Some(err.to_string())),
+            }
+        });
+    };
+
+    view! {
+        <div class="flex w-full justify-between px-2">
+            <input type="text" class="w-1/2" placeholder="Preset name"
+                prop:value=move || name.get() on:input=move |ev| name.set(event_target_value(&ev)) />
+            <span>
+                <button class="px-2 bg-button" on:click=export>"Export"</button>
+                <label class="px-2 bg-button cursor-pointer">
+                    "Import"
+                    <input type="file" class="hidden" accept=".json,application/json" on:change=import />
+                </label>
+                <button class="px-2 bg-button" on:click=share>"Copy link"</button>
+            </span>
+        </div>
+        {move || message.get().map(|note| view! { <p class="w-full px-2 text-yellow-300">{note}</p> })}
+    }
+}
@@ -0,0 +1,167 @@
+//! Messages between the page and the conversion workers. Both sides exchange
+//! bincode encoded bytes, so the whole round trip can run natively in tests.
+//! A job carries the uploaded file rather than its pixels, so the worker does
+//! the decoding as well as the encoding.
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::color::flatten_alpha;
+use crate::convert::convert_image;
+use crate::error::ConversionError;
+use crate::metadata::{apply_color_profile, output_icc, write_metadata};
+use crate::options::EncodeOptions;
+use crate::recipe::Recipe;
+use crate::serde_image;
+use crate::upload::decode_upload;
+
+/// One image to convert, sent from the page to a worker.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConversionJob {
+    pub id: u64,
+    pub file: Vec<u8>, // the upload as it was read, decoded again by the worker
+    pub auto_orient: bool,
+    #[serde(with = "serde_image::image_format")]
+    pub format: ImageFormat,
+    pub options: EncodeOptions,
+    pub recipe: Recipe,
+}
+
+/// The encoded bytes for a job.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConversionResult {
+    pub id: u64,
+    pub result: Result<Vec<u8>, ConversionError>,
+}
+
+/// The step a job has reached, reported back while it runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStage {
+    Decoding,
+    Encoding,
+}
+
+/// Everything a worker sends back for a job: any number of progress updates,
+/// then exactly one result.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum WorkerReply {
+    Progress { id: u64, stage: JobStage },
+    Finished(ConversionResult),
+}
+
+impl ConversionJob {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("conversion jobs always serialize")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(bytes)
+    }
+
+    /// Does the actual work: decodes the file the way it was uploaded, runs
+    /// the recipe and encodes the result.
+    pub fn run(self, mut progress: impl FnMut(JobStage)) -> ConversionResult {
+        let ConversionJob { id, file, auto_orient, format, options, recipe } = self;
+
+        progress(JobStage::Decoding);
+        let result = decode_upload(String::new(), file, auto_orient)
+            .map_err(|err| ConversionError::Decode(err.to_string()))
+            .and_then(|(info, img)| {
+                let img = apply_color_profile(img, &info.metadata, &options.metadata)?;
+                let img = flatten_alpha(recipe.apply(img)?, format, &options)?;
+                progress(JobStage::Encoding);
+                let encoded = convert_image(img, format, &options, output_icc(&info.metadata, &options.metadata))?;
+                Ok(write_metadata(encoded, format, &info.metadata, &options.metadata))
+            });
+
+        ConversionResult { id, result }
+    }
+}
+
+impl WorkerReply {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("worker replies always serialize")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> bincode::Result<Self> {
+        bincode::deserialize(bytes)
+    }
+}
+
+/// Entry point for the worker: takes a serialized `ConversionJob` and passes
+/// each serialized `WorkerReply` for it to `reply`.
+pub fn handle_message(bytes: &[u8], mut reply: impl FnMut(Vec<u8>)) -> bincode::Result<()> {
+    let job = ConversionJob::from_bytes(bytes)?;
+    let id = job.id;
+    let result = job.run(|stage| reply(WorkerReply::Progress { id, stage }.to_bytes()));
+    reply(WorkerReply::Finished(result).to_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, Rgba, RgbaImage};
+    use super::*;
+
+    fn job(img: &DynamicImage, format: ImageFormat) -> ConversionJob {
+        let mut file = Vec::new();
+        img.write_to(&mut Cursor::new(&mut file), ImageFormat::Png).unwrap();
+        ConversionJob { id: 7, file, auto_orient: true, format, options: EncodeOptions::default(), recipe: Recipe::default() }
+    }
+
+    #[test]
+    fn job_round_trips_through_bytes() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 2, Rgba([10, 20, 30, 40])));
+        let job = job(&img, ImageFormat::Tiff);
+        assert_eq!(ConversionJob::from_bytes(&job.to_bytes()).unwrap(), job);
+    }
+
+    fn replies(job: &ConversionJob) -> Vec<WorkerReply> {
+        let mut replies = Vec::new();
+        handle_message(&job.to_bytes(), |bytes| replies.push(WorkerReply::from_bytes(&bytes).unwrap())).unwrap();
+        replies
+    }
+
+    #[test]
+    fn handle_message_converts_the_image() {
+        let img = DynamicImage::ImageRgba8(RgbaImage::from_fn(8, 8, |x, y| Rgba([x as u8 * 30, y as u8 * 30, 0, 255])));
+        let replies = replies(&job(&img, ImageFormat::Png));
+
+        let [
+            WorkerReply::Progress { id: 7, stage: JobStage::Decoding },
+            WorkerReply::Progress { id: 7, stage: JobStage::Encoding },
+            WorkerReply::Finished(ConversionResult { id: 7, result: Ok(encoded) }),
+        ] = replies.as_slice() else {
+            panic!("unexpected replies {replies:?}");
+        };
+        let decoded = image::load_from_memory_with_format(encoded, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn handle_message_reports_conversion_errors() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(300, 300));
+        let replies = replies(&job(&img, ImageFormat::Ico));
+
+        assert_eq!(replies.last(), Some(&WorkerReply::Finished(ConversionResult {
+            id: 7,
+            result: Err(ConversionError::DimensionLimitsExceeded { width: 300, height: 300, max: 256 }),
+        })));
+    }
+
+    #[test]
+    fn handle_message_reports_undecodable_files() {
+        let job = ConversionJob { file: b"not an image".to_vec(), ..job(&DynamicImage::new_rgb8(1, 1), ImageFormat::Png) };
+        let replies = replies(&job);
+
+        assert_eq!(replies.len(), 2);
+        assert!(matches!(replies[1], WorkerReply::Finished(ConversionResult { id: 7, result: Err(ConversionError::Decode(_)) })));
+    }
+
+    #[test]
+    fn garbage_is_not_a_job() {
+        assert!(handle_message(&[1, 2, 3], |_| panic!("garbage got a reply")).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+//! Recipes, the ordered steps run on each queued image between decoding and
+//! encoding. They serialize like the rest of the options, so a recipe can be
+//! saved, shared and sent to the workers as it is.
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::options::{CropBox, ResizeOptions};
+use crate::error::ConversionError;
+use crate::resize::{requested_size, resize_image};
+use crate::transform::{crop, crop_rect, rotate, rotated_size};
+
+/// Steps run in order, skipping the disabled ones.
+#[derive(Clone, Debug, PartialEq, Default, Serialize, Deserialize)]
+pub struct Recipe {
+    pub operations: Vec<Operation>,
+}
+
+/// A step of a recipe, kept in place while turned off.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Operation {
+    pub enabled: bool,
+    pub kind: OperationKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OperationKind {
+    /// `None` until a box has been drawn.
+    Crop(Option<CropBox>),
+    /// Clockwise, corners uncovered by angles other than quarter turns get `fill`.
+    Rotate { degrees: f32, fill: [u8; 3] },
+    Flip { horizontal: bool, vertical: bool },
+    Resize(ResizeOptions),
+}
+
+impl Recipe {
+    /// Fails when a step would make an image too large to hold, see
+    /// `resize::MAX_PIXELS`.
+    pub fn apply(&self, img: DynamicImage) -> Result<DynamicImage, ConversionError> {
+        self.enabled().try_fold(img, |img, kind| kind.apply(img))
+    }
+
+    /// The size a `width`x`height` image has after `apply`, or is asked to
+    /// have when `apply` refuses it.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        self.enabled().fold((width, height), |(width, height), kind| kind.output_size(width, height))
+    }
+
+    /// The steps that run before the one at `index`.
+    pub fn before(&self, index: usize) -> Recipe {
+        Recipe { operations: self.operations[..index.min(self.operations.len())].to_vec() }
+    }
+
+    /// The same recipe for an image `factor` times the size it was made for.
+    pub fn scaled(&self, factor: f64) -> Recipe {
+        let operations = self.operations.iter()
+            .map(|op| Operation { kind: op.kind.scaled(factor), ..*op })
+            .collect();
+        Recipe { operations }
+    }
+
+    fn enabled(&self) -> impl Iterator<Item = &OperationKind> {
+        self.operations.iter().filter(|op| op.enabled).map(|op| &op.kind)
+    }
+}
+
+impl Operation {
+    pub fn new(kind: OperationKind) -> Operation {
+        Operation { enabled: true, kind }
+    }
+}
+
+impl OperationKind {
+    /// A fresh step of each kind, in the order they're offered.
+    pub const ALL: [OperationKind; 4] = [
+        OperationKind::Crop(None),
+        OperationKind::Rotate { degrees: 90.0, fill: [255, 255, 255] },
+        OperationKind::Flip { horizontal: true, vertical: false },
+        OperationKind::Resize(ResizeOptions::DEFAULT),
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            OperationKind::Crop(_) => "Crop",
+            OperationKind::Rotate { .. } => "Rotate",
+            OperationKind::Flip { .. } => "Flip",
+            OperationKind::Resize(_) => "Resize",
+        }
+    }
+
+    pub fn apply(&self, img: DynamicImage) -> Result<DynamicImage, ConversionError> {
+        Ok(match *self {
+            OperationKind::Crop(Some(rect)) => crop(img, rect),
+            OperationKind::Crop(None) => img,
+            OperationKind::Rotate { degrees, fill } => rotate(img, degrees, fill),
+            OperationKind::Flip { horizontal, vertical } => {
+                let img = if horizontal { img.fliph() } else { img };
+                if vertical { img.flipv() } else { img }
+            },
+            OperationKind::Resize(options) => return resize_image(img, &options),
+        })
+    }
+
+    /// The size a `width`x`height` image has after `apply`.
+    pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            OperationKind::Crop(Some(rect)) => {
+                let rect = crop_rect(width, height, *rect);
+                (rect.width, rect.height)
+            },
+            OperationKind::Rotate { degrees, .. } => rotated_size(width, height, *degrees),
+            OperationKind::Resize(options) => requested_size(width, height, options),
+            OperationKind::Crop(None) | OperationKind::Flip { .. } => (width, height),
+        }
+    }
+
+    /// The same step for an image `factor` times the size, sizes in pixels
+    /// are scaled along.
+    pub fn scaled(&self, factor: f64) -> OperationKind {
+        let scale = |length: u32| ((f64::from(length) * factor).round() as u32).max(1);
+        match *self {
+            OperationKind::Crop(Some(rect)) => OperationKind::Crop(Some(CropBox {
+                x: (f64::from(rect.x) * factor).round() as u32,
+                y: (f64::from(rect.y) * factor).round() as u32,
+                width: scale(rect.width),
+                height: scale(rect.height),
+            })),
+            OperationKind::Resize(options) => OperationKind::Resize(ResizeOptions {
+                width: scale(options.width),
+                height: scale(options.height),
+                longest_edge: scale(options.longest_edge),
+                ..options
+            }),
+            kind => kind,
+        }
+    }
+}
+
+/// An image shrunk once so neither side is over `max_side`, for previewing
+/// recipes on it. Applying a recipe scales its pixel sizes down to match, so
+/// redoing it on every change of the recipe stays cheap.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreviewSource {
+    image: DynamicImage,
+    width: u32,
+    height: u32,
+}
+
+impl PreviewSource {
+    pub fn new(img: &DynamicImage, max_side: u32) -> PreviewSource {
+        let image = if img.width().max(img.height()) > max_side {
+            img.thumbnail(max_side, max_side)
+        } else {
+            img.clone()
+        };
+        PreviewSource { image, width: img.width(), height: img.height() }
+    }
+
+    /// The size `recipe` gives the full image.
+    pub fn output_size(&self, recipe: &Recipe) -> (u32, u32) {
+        recipe.output_size(self.width, self.height)
+    }
+
+    /// The shrunk image run through `recipe`.
+    pub fn apply(&self, recipe: &Recipe) -> Result<DynamicImage, ConversionError> {
+        let factor = f64::from(self.image.width()) / f64::from(self.width);
+        recipe.scaled(factor).apply(self.image.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb, RgbImage};
+    use super::*;
+    use crate::options::ResizeMode;
+
+    fn numbered(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, 0])))
+    }
+
+    fn recipe() -> Recipe {
+        Recipe {
+            operations: vec![
+                Operation::new(OperationKind::Crop(Some(CropBox { x: 10, y: 0, width: 160, height: 96 }))),
+                Operation::new(OperationKind::Rotate { degrees: 90.0, fill: [0, 0, 0] }),
+                Operation::new(OperationKind::Flip { horizontal: true, vertical: false }),
+                Operation::new(OperationKind::Resize(ResizeOptions { mode: ResizeMode::Percent, percent: 50, ..Default::default() })),
+            ],
+        }
+    }
+
+    #[test]
+    fn steps_run_in_order() {
+        let expected = numbered(200, 100).crop_imm(10, 0, 160, 96).rotate90().fliph().resize_exact(48, 80, image::imageops::FilterType::Lanczos3);
+
+        let applied = recipe().apply(numbered(200, 100)).unwrap();
+        assert_eq!(applied.to_rgb8(), expected.to_rgb8());
+        assert_eq!(recipe().output_size(200, 100), (48, 80));
+    }
+
+    #[test]
+    fn disabled_steps_are_skipped() {
+        let mut recipe = recipe();
+        recipe.operations[1].enabled = false;
+        recipe.operations[3].enabled = false;
+        assert_eq!(recipe.output_size(200, 100), (160, 96));
+        assert_eq!(recipe.before(1).output_size(200, 100), (160, 96));
+    }
+
+    #[test]
+    fn previews_keep_the_proportions() {
+        let source = PreviewSource::new(&numbered(200, 100), 50);
+        let shown = source.apply(&recipe()).unwrap();
+        let (width, height) = source.output_size(&recipe());
+        assert_eq!((width, height), (48, 80));
+        assert_eq!(shown.dimensions(), (width / 4, height / 4));
+    }
+
+    #[test]
+    fn small_images_are_previewed_as_they_are() {
+        let source = PreviewSource::new(&numbered(200, 100), 480);
+        assert_eq!(source.apply(&recipe()).unwrap().to_rgb8(), recipe().apply(numbered(200, 100)).unwrap().to_rgb8());
+    }
+
+    #[test]
+    fn recipes_survive_serialization() {
+        let bytes = bincode::serialize(&recipe()).unwrap();
+        assert_eq!(bincode::deserialize::<Recipe>(&bytes).unwrap(), recipe());
+    }
+}
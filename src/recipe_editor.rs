@@ -0,0 +1,370 @@
+//! Editing the recipe run on queued images: a row of settings per step and a
+//! preview of the first selected upload going through all of them.
+
+use leptos::{component, create_effect, create_memo, create_node_ref, create_rw_signal, create_signal, ev, event_target_checked, event_target_value, html, use_context, view, For, IntoView, Memo, RwSignal, Show, Signal, SignalGet, SignalGetUntracked, SignalSet, SignalUpdate, SignalWith, SignalWithUntracked};
+use web_image_converter::options::{parse_hex_color, to_hex_color, CropAspect, ResizeFilter, ResizeMode, ResizeOptions};
+use web_image_converter::recipe::{Operation, OperationKind, PreviewSource, Recipe};
+use web_image_converter::transform::{crop_rect, drag_crop};
+
+use crate::app::{NumberSetting, OptionSelect};
+use crate::{generate_sample_image, generate_unique_key, AppState};
+
+const PREVIEW_SIZE: u32 = 480;
+
+/// A step as the editor holds it, rows keep their own state while steps are moved.
+#[derive(Clone, Debug, PartialEq)]
+struct Step {
+    id: String,
+    enabled: RwSignal<bool>,
+    kind: RwSignal<OperationKind>,
+}
+
+impl Step {
+    fn new(operation: Operation) -> Step {
+        Step { id: generate_unique_key(), enabled: create_rw_signal(operation.enabled), kind: create_rw_signal(operation.kind) }
+    }
+
+    fn operation(&self) -> Operation {
+        Operation { enabled: self.enabled.get(), kind: self.kind.get() }
+    }
+}
+
+#[component]
+pub fn RecipeEditor(recipe: RwSignal<Recipe>) -> impl IntoView {
+    let steps = create_rw_signal(recipe.get_untracked().operations.into_iter().map(Step::new).collect::<Vec<_>>());
+    create_effect(move |_| {
+        let operations = steps.with(|steps| steps.iter().map(Step::operation).collect());
+        recipe.set(Recipe { operations });
+    });
+
+    let add = move |kind| steps.update(|steps| steps.push(Step::new(Operation::new(kind))));
+
+    // the first selected upload, shrunk once and shared by every preview below
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+    let selected = create_memo(move |_| app_state.input_files.with(|files| {
+        files.iter().find(|img| img.is_selected.get()).map(|img| img.id.clone())
+    }));
+    let source = create_memo(move |_| {
+        let id = selected.get()?;
+        app_state.input_files.with_untracked(|files| {
+            files.iter().find(|img| img.id == id).map(|img| PreviewSource::new(&img.image, PREVIEW_SIZE))
+        })
+    });
+
+    view! {
+        <div class="flex w-full justify-between px-2">
+            "Steps"
+            <span>
+                {OperationKind::ALL.iter().map(|kind| {
+                    let kind = *kind;
+                    view! { <button class="px-2 bg-button" on:click=move |_| add(kind)>{format!("+ {}", kind.label())}</button> }
+                }).collect::<Vec<_>>()}
+            </span>
+        </div>
+        <For each=move || steps.get() key=|step| step.id.clone()
+            children=move |step| view! { <StepRow step steps recipe source /> } />
+        <RecipePreview recipe source />
+    }
+}
+
+#[component]
+fn StepRow(step: Step, steps: RwSignal<Vec<Step>>, recipe: RwSignal<Recipe>, source: Memo<Option<PreviewSource>>) -> impl IntoView {
+    let Step { id, enabled, kind } = step;
+    let index = create_memo(move |_| steps.with(|steps| steps.iter().position(|step| step.id == id).unwrap_or_default()));
+    let last = move || steps.with(Vec::len).saturating_sub(1);
+    // the crop is drawn on the image as it reaches this step
+    let before = Signal::derive(move || recipe.with(|recipe| recipe.before(index.get())));
+
+    let move_to = move |to: usize| {
+        let from = index.get_untracked();
+        steps.update(|steps| {
+            let step = steps.remove(from);
+            steps.insert(to, step);
+        });
+    };
+    let remove = move |_| {
+        let at = index.get_untracked();
+        steps.update(|steps| { steps.remove(at); });
+    };
+
+    let settings = match kind.get_untracked() {
+        OperationKind::Crop(_) => view! { <CropSettings kind before source /> }.into_view(),
+        OperationKind::Rotate { .. } => view! { <RotateSettings kind /> }.into_view(),
+        OperationKind::Flip { .. } => view! { <FlipSettings kind /> }.into_view(),
+        OperationKind::Resize(_) => view! { <ResizeSettings kind /> }.into_view(),
+    };
+
+    view! {
+        <div class=move || if enabled.get() { "flex flex-col w-full py-1" } else { "flex flex-col w-full py-1 opacity-50" }>
+            <div class="flex w-full justify-between px-2">
+                <label>
+                    <input type="checkbox" checked=enabled.get_untracked()
+                        on:change=move |ev| enabled.set(event_target_checked(&ev)) />
+                    " " {kind.get_untracked().label()}
+                </label>
+                <span>
+                    <button class="px-2 bg-button" disabled=move || index.get() == 0
+                        on:click=move |_| move_to(index.get_untracked() - 1)>"Up"</button>
+                    <button class="px-2 bg-button" disabled=move || index.get() == last()
+                        on:click=move |_| move_to(index.get_untracked() + 1)>"Down"</button>
+                    <button class="px-2 bg-button" on:click=remove>"Remove"</button>
+                </span>
+            </div>
+            {settings}
+        </div>
+    }
+}
+
+/// The first selected upload run through the whole recipe.
+#[component]
+fn RecipePreview(recipe: RwSignal<Recipe>, source: Memo<Option<PreviewSource>>) -> impl IntoView {
+    // (width, height, preview) of the converted image, or why it can't be made
+    let shown = create_memo(move |_| {
+        let recipe = recipe.get();
+        if recipe.operations.is_empty() {
+            return None;
+        }
+        source.with(|source| {
+            let source = source.as_ref()?;
+            let (width, height) = source.output_size(&recipe);
+            let small = source.apply(&recipe)
+                .map(|small| generate_sample_image(&small, PREVIEW_SIZE, &mut Vec::new()))
+                .map_err(|err| err.to_string());
+            Some((width, height, small))
+        })
+    });
+
+    move || shown.get().map(|(width, height, src)| match src {
+        Ok(src) => view! {
+            <div class="flex flex-col items-center w-full px-2">
+                <img class="block max-w-full" src=src />
+                {format!("Preview, {width}x{height}")}
+            </div>
+        },
+        Err(err) => view! {
+            <div class="flex flex-col items-center w-full px-2">
+                <p class="text-yellow-300">{err}</p>
+            </div>
+        },
+    })
+}
+
+/// Draws the crop box on a larger preview of the first selected upload. The
+/// box is kept in that image's pixels and used for every image converted
+/// with the recipe, images it doesn't fit get a centered crop instead.
+#[component]
+fn CropSettings(kind: RwSignal<OperationKind>, before: Signal<Recipe>, source: Memo<Option<PreviewSource>>) -> impl IntoView {
+    let app_state = use_context::<AppState>().expect("AppState not provided");
+    let crop = create_rw_signal(match kind.get_untracked() {
+        OperationKind::Crop(crop) => crop,
+        _ => None,
+    });
+    create_effect(move |_| kind.set(OperationKind::Crop(crop.get())));
+
+    let (open, set_open) = create_signal(false);
+    let (aspect, set_aspect) = create_signal(CropAspect::Free);
+    let (drag_start, set_drag_start) = create_signal(None);
+    let drawn = create_rw_signal(None);
+    let frame = create_node_ref::<html::Div>();
+
+    // (width, height, preview) of the image the box is drawn on
+    let reference = create_memo(move |_| {
+        if !open.get() {
+            return None;
+        }
+        let before = before.get();
+        source.with(|source| {
+            let source = source.as_ref()?;
+            let (width, height) = source.output_size(&before);
+            let small = source.apply(&before).ok()?; // the recipe preview says why
+            Some((width, height, generate_sample_image(&small, PREVIEW_SIZE, &mut Vec::new())))
+        })
+    });
+
+    // where the pointer is, in pixels of the reference image
+    let pointer = move |ev: &ev::MouseEvent| {
+        let rect = frame.get_untracked()?.get_bounding_client_rect();
+        let (width, height, _) = reference.get_untracked()?;
+        let x = ((f64::from(ev.client_x()) - rect.left()) / rect.width()).clamp(0.0, 1.0) * f64::from(width);
+        let y = ((f64::from(ev.client_y()) - rect.top()) / rect.height()).clamp(0.0, 1.0) * f64::from(height);
+        Some((x, y))
+    };
+    let on_down = move |ev: ev::MouseEvent| {
+        ev.prevent_default();
+        set_drag_start.set(pointer(&ev));
+    };
+    let on_move = move |ev: ev::MouseEvent| {
+        let (Some(start), Some(end), Some((width, height, _))) = (drag_start.get_untracked(), pointer(&ev), reference.get_untracked()) else {
+            return;
+        };
+        drawn.set(Some(drag_crop(start, end, aspect.get_untracked().ratio(), width, height)));
+    };
+    let on_up = move |_| {
+        if drag_start.get_untracked().is_none() {
+            return;
+        }
+        set_drag_start.set(None);
+        if let Some(drawn) = drawn.get_untracked() {
+            crop.set(Some(drawn));
+        }
+        drawn.set(None);
+    };
+
+    let crop_style = move || {
+        let (width, height, _) = reference.get()?;
+        let crop = crop_rect(width, height, drawn.get().or(crop.get())?);
+        let percent = |value: u32, of: u32| format!("{}%", f64::from(value) / f64::from(of) * 100.0);
+        Some(format!(
+            "left: {}; top: {}; width: {}; height: {}",
+            percent(crop.x, width), percent(crop.y, height), percent(crop.width, width), percent(crop.height, height),
+        ))
+    };
+    let summary = move || match crop.get() {
+        Some(crop) => format!("{}x{} at {}, {}", crop.width, crop.height, crop.x, crop.y),
+        None => "No box drawn yet".to_string(),
+    };
+    let fallbacks = move || {
+        let crop = crop.get()?;
+        let before = before.get();
+        let too_small = app_state.input_files.with(|files| {
+            files.iter()
+                .filter(|img| img.is_selected.get())
+                .map(|img| before.output_size(img.image.width(), img.image.height()))
+                .filter(|(width, height)| crop.width > *width || crop.height > *height)
+                .count()
+        });
+        (too_small > 0).then(|| format!("{too_small} selected images are smaller than the crop and get a centered one"))
+    };
+
+    view! {
+        <div class="flex w-full justify-between px-2">
+            {summary}
+            <span>
+                <button class="px-2 bg-button" on:click=move |_| set_open.update(|open| *open = !*open)>
+                    {move || if open.get() { "Done" } else { "Edit crop" }}
+                </button>
+                <button class="px-2 bg-button" on:click=move |_| crop.set(None)>"Clear"</button>
+            </span>
+        </div>
+        {move || fallbacks().map(|note| view! { <p class="w-full px-2 text-yellow-300">{note}</p> })}
+        <Show when=move || open.get()>
+            <OptionSelect label="Aspect ratio" value=aspect.get_untracked()
+                on_change=move |new_aspect: CropAspect| set_aspect.set(new_aspect) />
+            {move || match reference.get() {
+                None => view! { <p class="px-2 text-yellow-300">"Select an uploaded image to draw the crop on"</p> }.into_view(),
+                Some((_, _, preview)) => view! {
+                    <div class="relative select-none cursor-crosshair" node_ref=frame
+                        on:mousedown=on_down on:mousemove=on_move on:mouseup=on_up on:mouseleave=on_up>
+                        <img class="block max-w-full" src=preview draggable="false" />
+                        <Show when=move || crop_style().is_some()>
+                            <div class="absolute border-2 border-dashed border-white pointer-events-none"
+                                style=move || crop_style().unwrap_or_default() />
+                        </Show>
+                    </div>
+                }.into_view(),
+            }}
+        </Show>
+    }
+}
+
+#[component]
+fn RotateSettings(kind: RwSignal<OperationKind>) -> impl IntoView {
+    let (initial_degrees, initial_fill) = match kind.get_untracked() {
+        OperationKind::Rotate { degrees, fill } => (degrees, fill),
+        _ => (0.0, [255, 255, 255]),
+    };
+    let degrees = create_rw_signal(initial_degrees);
+    let fill = create_rw_signal(initial_fill);
+    create_effect(move |_| kind.set(OperationKind::Rotate { degrees: degrees.get(), fill: fill.get() }));
+
+    let update_angle = move |ev| {
+        if let Ok(angle) = event_target_value(&ev).parse::<f32>() {
+            degrees.set(angle);
+        }
+    };
+    let update_fill = move |ev| {
+        if let Some(color) = parse_hex_color(&event_target_value(&ev)) {
+            fill.set(color);
+        }
+    };
+
+    view! {
+        <label class="flex w-full justify-between px-2">
+            "Degrees clockwise"
+            <input type="number" class="w-16" step="0.1" value=initial_degrees on:change=update_angle />
+        </label>
+        <Show when=move || degrees.get() % 90.0 != 0.0>
+            <label class="flex w-full justify-between px-2">
+                "Corner fill"
+                <input type="color" value=to_hex_color(fill.get_untracked()) on:input=update_fill />
+            </label>
+        </Show>
+    }
+}
+
+#[component]
+fn FlipSettings(kind: RwSignal<OperationKind>) -> impl IntoView {
+    let (initial_horizontal, initial_vertical) = match kind.get_untracked() {
+        OperationKind::Flip { horizontal, vertical } => (horizontal, vertical),
+        _ => (false, false),
+    };
+    let horizontal = create_rw_signal(initial_horizontal);
+    let vertical = create_rw_signal(initial_vertical);
+    create_effect(move |_| kind.set(OperationKind::Flip { horizontal: horizontal.get(), vertical: vertical.get() }));
+
+    view! {
+        <label class="flex w-full justify-between px-2">
+            "Horizontally"
+            <input type="checkbox" checked=initial_horizontal
+                on:change=move |ev| horizontal.set(event_target_checked(&ev)) />
+        </label>
+        <label class="flex w-full justify-between px-2">
+            "Vertically"
+            <input type="checkbox" checked=initial_vertical
+                on:change=move |ev| vertical.set(event_target_checked(&ev)) />
+        </label>
+    }
+}
+
+#[component]
+fn ResizeSettings(kind: RwSignal<OperationKind>) -> impl IntoView {
+    let options = create_rw_signal(match kind.get_untracked() {
+        OperationKind::Resize(options) => options,
+        _ => ResizeOptions::default(),
+    });
+    create_effect(move |_| kind.set(OperationKind::Resize(options.get())));
+
+    let (mode, set_mode) = create_signal(options.get_untracked().mode);
+    let uses_box = move || matches!(mode.get(), ResizeMode::Exact | ResizeMode::Fit | ResizeMode::Fill);
+    // read when shown, so values entered before hiding them come back
+    let current = move || options.get_untracked();
+
+    view! {
+        <OptionSelect label="Mode" value=mode.get_untracked()
+            on_change=move |new_mode: ResizeMode| {
+                set_mode.set(new_mode);
+                options.update(|o| o.mode = new_mode);
+            } />
+        <Show when=uses_box>
+            <NumberSetting label="Width" min=1 max=65535 value=current().width
+                on_change=move |width| options.update(|o| o.width = width) />
+            <NumberSetting label="Height" min=1 max=65535 value=current().height
+                on_change=move |height| options.update(|o| o.height = height) />
+        </Show>
+        <Show when=move || mode.get() == ResizeMode::Percent>
+            <NumberSetting label="Percent" min=1 max=1000 value=current().percent
+                on_change=move |percent| options.update(|o| o.percent = percent) />
+        </Show>
+        <Show when=move || mode.get() == ResizeMode::LongestEdge>
+            <NumberSetting label="Longest edge" min=1 max=65535 value=current().longest_edge
+                on_change=move |edge| options.update(|o| o.longest_edge = edge) />
+        </Show>
+        <OptionSelect label="Resize filter" value=current().filter
+            on_change=move |filter: ResizeFilter| options.update(|o| o.filter = filter) />
+        <label class="flex w-full justify-between px-2">
+            "Never upscale"
+            <input type="checkbox" checked=current().never_upscale
+                on:change=move |ev| options.update(|o| o.never_upscale = event_target_checked(&ev)) />
+        </label>
+    }
+}
@@ -0,0 +1,152 @@
+//! The resize step of a recipe.
+
+use image::DynamicImage;
+
+use crate::error::ConversionError;
+use crate::options::{ResizeMode, ResizeOptions};
+
+/// The most pixels a resize may produce, including the image Fill scales
+/// before cutting it. Much more and the buffers don't fit a wasm32 memory.
+pub const MAX_PIXELS: u64 = 100_000_000;
+
+/// The size a `width`x`height` image is resized to, `None` when it stays as
+/// it is. Fails when that is over `MAX_PIXELS`.
+pub fn target_size(width: u32, height: u32, options: &ResizeOptions) -> Result<Option<(u32, u32)>, ConversionError> {
+    let size = requested_size(width, height, options);
+    let largest = match options.mode {
+        ResizeMode::Fill => scaled(width, height, scale(width, height, options)),
+        _ => size,
+    };
+    if u64::from(largest.0) * u64::from(largest.1) > MAX_PIXELS {
+        return Err(ConversionError::TooManyPixels { width: largest.0, height: largest.1, max: MAX_PIXELS });
+    }
+    Ok((size != (width, height)).then_some(size))
+}
+
+/// The size the options ask for, whether or not it can be made.
+pub(crate) fn requested_size(width: u32, height: u32, options: &ResizeOptions) -> (u32, u32) {
+    match options.mode {
+        ResizeMode::Fill => {
+            let (scaled_width, scaled_height) = scaled(width, height, scale(width, height, options));
+            (scaled_width.min(options.width.max(1)), scaled_height.min(options.height.max(1)))
+        },
+        _ => scaled_to(width, height, options),
+    }
+}
+
+/// Resizes `img` as the options say. Fill scales the image to cover the box
+/// and cuts what sticks out evenly from both sides.
+pub fn resize_image(img: DynamicImage, options: &ResizeOptions) -> Result<DynamicImage, ConversionError> {
+    let Some((width, height)) = target_size(img.width(), img.height(), options)? else {
+        return Ok(img);
+    };
+    let filter = options.filter.into();
+
+    if options.mode != ResizeMode::Fill {
+        return Ok(img.resize_exact(width, height, filter));
+    }
+
+    let (scaled_width, scaled_height) = scaled(img.width(), img.height(), scale(img.width(), img.height(), options));
+    Ok(img.resize_exact(scaled_width, scaled_height, filter)
+        .crop_imm((scaled_width - width) / 2, (scaled_height - height) / 2, width, height))
+}
+
+// the size for every mode but Fill
+fn scaled_to(width: u32, height: u32, options: &ResizeOptions) -> (u32, u32) {
+    if options.mode != ResizeMode::Exact {
+        return scaled(width, height, scale(width, height, options));
+    }
+
+    let (box_width, box_height) = (options.width.max(1), options.height.max(1));
+    if !options.never_upscale {
+        return (box_width, box_height);
+    }
+    // one factor for both sides, so the box keeps its aspect ratio when it is shrunk to fit
+    let factor = (f64::from(width) / f64::from(box_width))
+        .min(f64::from(height) / f64::from(box_height))
+        .min(1.0);
+    scaled(box_width, box_height, factor)
+}
+
+// the factor both sides are multiplied by in the modes that keep the aspect ratio
+fn scale(width: u32, height: u32, options: &ResizeOptions) -> f64 {
+    let (width, height) = (f64::from(width), f64::from(height));
+    let (box_width, box_height) = (f64::from(options.width.max(1)), f64::from(options.height.max(1)));
+
+    let scale = match options.mode {
+        ResizeMode::Fit => (box_width / width).min(box_height / height),
+        ResizeMode::Fill => (box_width / width).max(box_height / height),
+        ResizeMode::Percent => f64::from(options.percent) / 100.0,
+        ResizeMode::LongestEdge => f64::from(options.longest_edge.max(1)) / width.max(height),
+        ResizeMode::Exact => 1.0,
+    };
+    if options.never_upscale { scale.min(1.0) } else { scale }
+}
+
+fn scaled(width: u32, height: u32, scale: f64) -> (u32, u32) {
+    let side = |length: u32| ((f64::from(length) * scale).round() as u32).max(1);
+    (side(width), side(height))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb, RgbImage};
+    use super::*;
+    use crate::options::ResizeFilter;
+
+    fn options(mode: ResizeMode) -> ResizeOptions {
+        ResizeOptions { mode, width: 100, height: 100, percent: 50, longest_edge: 300, never_upscale: false, ..Default::default() }
+    }
+
+    #[test]
+    fn sizes_for_each_mode() {
+        assert_eq!(target_size(400, 200, &options(ResizeMode::Exact)), Ok(Some((100, 100))));
+        assert_eq!(target_size(400, 200, &options(ResizeMode::Fit)), Ok(Some((100, 50))));
+        assert_eq!(target_size(400, 200, &options(ResizeMode::Fill)), Ok(Some((100, 100))));
+        assert_eq!(target_size(400, 200, &options(ResizeMode::Percent)), Ok(Some((200, 100))));
+        assert_eq!(target_size(400, 200, &options(ResizeMode::LongestEdge)), Ok(Some((300, 150))));
+    }
+
+    #[test]
+    fn never_upscale_keeps_small_images() {
+        let small = |mode| ResizeOptions { never_upscale: true, ..options(mode) };
+        assert_eq!(target_size(40, 20, &small(ResizeMode::Fit)), Ok(None));
+        assert_eq!(target_size(400, 20, &small(ResizeMode::Fill)), Ok(Some((100, 20))));
+
+        assert_eq!(target_size(40, 20, &options(ResizeMode::Fit)), Ok(Some((100, 50))));
+    }
+
+    #[test]
+    fn never_upscale_keeps_the_exact_aspect_ratio() {
+        let exact = |width, height| ResizeOptions { width, height, never_upscale: true, ..options(ResizeMode::Exact) };
+        assert_eq!(target_size(100, 100, &exact(400, 20)), Ok(Some((100, 5))));
+        assert_eq!(target_size(40, 20, &exact(100, 100)), Ok(Some((20, 20))));
+        assert_eq!(target_size(400, 200, &exact(100, 100)), Ok(Some((100, 100))));
+    }
+
+    #[test]
+    fn huge_sizes_are_refused() {
+        let exact = ResizeOptions { width: 65535, height: 65535, ..options(ResizeMode::Exact) };
+        assert_eq!(target_size(100, 100, &exact), Err(ConversionError::TooManyPixels { width: 65535, height: 65535, max: MAX_PIXELS }));
+
+        let percent = ResizeOptions { percent: 1000, ..options(ResizeMode::Percent) };
+        assert!(target_size(4000, 3000, &percent).is_err());
+        assert!(resize_image(DynamicImage::new_rgb8(4000, 3000), &percent).is_err());
+
+        // Fill scales to cover the box before cutting, that image counts too
+        let fill = ResizeOptions { width: 20000, height: 1, ..options(ResizeMode::Fill) };
+        assert!(target_size(1, 10000, &fill).is_err());
+    }
+
+    #[test]
+    fn fill_crops_the_center() {
+        // left and right thirds red, middle green
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 100, |x, _| {
+            if (100..200).contains(&x) { Rgb([0, 255, 0]) } else { Rgb([255, 0, 0]) }
+        }));
+
+        let filled = resize_image(img, &ResizeOptions { filter: ResizeFilter::Nearest, ..options(ResizeMode::Fill) }).unwrap();
+        assert_eq!(filled.dimensions(), (100, 100));
+        assert!(filled.to_rgb8().pixels().all(|p| p == &Rgb([0, 255, 0])));
+    }
+}
@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use leptos::{create_rw_signal, RwSignal, SignalGetUntracked, SignalSet};
+
+/// Bookkeeping for the conversion queue. The queue task sleeps on `wakeups`
+/// and is only woken when there may be something to start: new work was
+/// queued, a job finished or the queue was resumed.
+pub struct Scheduler {
+    wake: UnboundedSender<()>,
+    wakeups: RefCell<Option<UnboundedReceiver<()>>>,
+    running: RefCell<HashSet<String>>, // ids of queued images that are being converted
+    cancelled: RefCell<HashSet<String>>, // running ids whose result should be thrown away
+    pub paused: RwSignal<bool>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        let (wake, wakeups) = unbounded();
+        Scheduler {
+            wake,
+            wakeups: RefCell::new(Some(wakeups)),
+            running: Default::default(),
+            cancelled: Default::default(),
+            paused: create_rw_signal(false),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn wake(&self) {
+        // only fails once the queue task is gone, nothing left to wake then
+        let _ = self.wake.unbounded_send(());
+    }
+
+    /// The receiving end of `wake`, can only be taken by one queue task.
+    pub fn take_wakeups(&self) -> UnboundedReceiver<()> {
+        self.wakeups.borrow_mut().take().expect("queue task already started")
+    }
+
+    pub fn pause(&self) {
+        self.paused.set(true);
+    }
+
+    pub fn resume(&self) {
+        self.paused.set(false);
+        self.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.get_untracked()
+    }
+
+    pub fn running_count(&self) -> usize {
+        self.running.borrow().len()
+    }
+
+    pub fn is_running(&self, id: &str) -> bool {
+        self.running.borrow().contains(id)
+    }
+
+    pub fn start(&self, id: &str) {
+        self.running.borrow_mut().insert(id.to_string());
+    }
+
+    /// Marks a running conversion as cancelled. A worker can't be stopped
+    /// halfway, so its result is dropped once it arrives.
+    pub fn cancel(&self, id: &str) {
+        if self.is_running(id) {
+            self.cancelled.borrow_mut().insert(id.to_string());
+        }
+    }
+
+    pub fn is_cancelled(&self, id: &str) -> bool {
+        self.cancelled.borrow().contains(id)
+    }
+
+    /// Returns whether the conversion was cancelled while it ran.
+    pub fn finish(&self, id: &str) -> bool {
+        self.running.borrow_mut().remove(id);
+        let cancelled = self.cancelled.borrow_mut().remove(id);
+        self.wake();
+        cancelled
+    }
+}
@@ -0,0 +1,67 @@
+//! `serde(with = ...)` helpers for the `image` types we send to workers and
+//! store, which don't implement serde themselves.
+
+pub mod image_format {
+    use image::ImageFormat;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(format: &ImageFormat, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(format.extensions_str()[0])
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ImageFormat, D::Error> {
+        let extension = String::deserialize(deserializer)?;
+        ImageFormat::from_extension(&extension)
+            .ok_or_else(|| D::Error::custom(format!("unknown image format `{extension}`")))
+    }
+}
+
+pub mod optional_image_format {
+    use image::ImageFormat;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(format: &Option<ImageFormat>, serializer: S) -> Result<S::Ok, S::Error> {
+        match format {
+            Some(format) => serializer.serialize_some(format.extensions_str()[0]),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<ImageFormat>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|extension| ImageFormat::from_extension(&extension)
+                .ok_or_else(|| D::Error::custom(format!("unknown image format `{extension}`"))))
+            .transpose()
+    }
+}
+
+pub mod extended_color_type {
+    use image::ExtendedColorType;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    const KNOWN: &[ExtendedColorType] = &[
+        ExtendedColorType::A8,
+        ExtendedColorType::L1, ExtendedColorType::La1, ExtendedColorType::Rgb1, ExtendedColorType::Rgba1,
+        ExtendedColorType::L2, ExtendedColorType::La2, ExtendedColorType::Rgb2, ExtendedColorType::Rgba2,
+        ExtendedColorType::L4, ExtendedColorType::La4, ExtendedColorType::Rgb4, ExtendedColorType::Rgba4,
+        ExtendedColorType::L8, ExtendedColorType::La8, ExtendedColorType::Rgb8, ExtendedColorType::Rgba8,
+        ExtendedColorType::L16, ExtendedColorType::La16, ExtendedColorType::Rgb16, ExtendedColorType::Rgba16,
+        ExtendedColorType::Bgr8, ExtendedColorType::Bgra8,
+        ExtendedColorType::Rgb32F, ExtendedColorType::Rgba32F,
+        ExtendedColorType::Cmyk8,
+    ];
+
+    pub fn serialize<S: Serializer>(color: &ExtendedColorType, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{color:?}"))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ExtendedColorType, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        KNOWN.iter().copied()
+            .find(|color| format!("{color:?}") == name)
+            .ok_or_else(|| D::Error::custom(format!("unknown color type `{name}`")))
+    }
+}
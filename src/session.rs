@@ -0,0 +1,198 @@
+//! What survives a page reload: every image in the three lists with its
+//! status and settings, without any of the page's signals. The session is a
+//! small record of its own, the uploaded and converted bytes are stored under
+//! one key each so they are only written once.
+
+use image::ImageFormat;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ConversionError;
+use crate::options::EncodeOptions;
+use crate::recipe::Recipe;
+use crate::serde_image;
+
+/// Sessions stored with another version are dropped rather than misread.
+pub const SESSION_VERSION: u32 = 1;
+
+pub const SESSION_KEY: &str = "session";
+/// The panel's settings, stored as a preset.
+pub const SETTINGS_KEY: &str = "settings";
+
+/// How much of the storage quota the browser grants a session may fill, the
+/// rest stays free for the browser's own estimate being off.
+const QUOTA_SHARE: f64 = 0.5;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    pub images: Vec<StoredImage>, // in list order, oldest first
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageList {
+    Uploaded,
+    Queued,
+    Converted,
+}
+
+/// Images that were being converted are queued again as `Pending`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StoredStatus {
+    Pending,
+    Done,
+    Failed(ConversionError),
+    Cancelled,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredImage {
+    pub id: String,
+    pub list: ImageList,
+    pub name: String,
+    pub folder: String,
+    pub selected: bool,
+    pub status: StoredStatus,
+    pub auto_oriented: bool, // decoded again on load, the same way it was uploaded
+    #[serde(with = "serde_image::optional_image_format")]
+    pub format: Option<ImageFormat>,
+    pub extension: String,
+    pub options: EncodeOptions,
+    pub recipe: Recipe,
+    pub time_completed: Option<String>,
+    pub input_size: u64,
+    pub output_size: u64, // 0 when there is no converted file
+}
+
+/// Where the uploaded bytes of the image with `id` are stored.
+pub fn input_key(id: &str) -> String {
+    format!("{id}.in")
+}
+
+/// Where the converted bytes of the image with `id` are stored.
+pub fn output_key(id: &str) -> String {
+    format!("{id}.out")
+}
+
+impl StoredImage {
+    pub fn input_key(&self) -> String {
+        input_key(&self.id)
+    }
+
+    pub fn output_key(&self) -> Option<String> {
+        (self.output_size > 0).then(|| output_key(&self.id))
+    }
+
+    fn stored_size(&self) -> u64 {
+        self.input_size + self.output_size
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Session { version: SESSION_VERSION, images: Vec::new() }
+    }
+}
+
+impl Session {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("sessions always serialize")
+    }
+
+    /// `None` for anything but a session of this version.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Session> {
+        bincode::deserialize::<Session>(bytes).ok()
+            .filter(|session| session.version == SESSION_VERSION)
+    }
+
+    /// The bytes the images take up in storage.
+    pub fn stored_size(&self) -> u64 {
+        self.images.iter().map(StoredImage::stored_size).sum()
+    }
+
+    /// Takes images out until the rest fit in `budget` bytes and returns the
+    /// ones taken. Converted images go first, as they can be made again from
+    /// their upload, then uploads and queued images last. The oldest image of
+    /// a list goes first.
+    pub fn fit_to(&mut self, budget: u64) -> Vec<StoredImage> {
+        let mut evicted = Vec::new();
+        for list in [ImageList::Converted, ImageList::Uploaded, ImageList::Queued] {
+            while self.stored_size() > budget {
+                let Some(index) = self.images.iter().position(|img| img.list == list) else {
+                    break;
+                };
+                evicted.push(self.images.remove(index));
+            }
+        }
+        evicted
+    }
+
+    /// The keys of every byte record the images need.
+    pub fn byte_keys(&self) -> Vec<String> {
+        self.images.iter()
+            .flat_map(|img| [Some(img.input_key()), img.output_key()])
+            .flatten()
+            .collect()
+    }
+}
+
+/// How many bytes a session may take up when the browser grants `quota`.
+pub fn storage_budget(quota: f64) -> u64 {
+    (quota * QUOTA_SHARE) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(id: &str, list: ImageList, input_size: u64, output_size: u64) -> StoredImage {
+        StoredImage {
+            id: id.to_string(),
+            list,
+            name: format!("{id}.png"),
+            folder: String::new(),
+            selected: false,
+            status: StoredStatus::Pending,
+            auto_oriented: true,
+            format: (list != ImageList::Uploaded).then_some(ImageFormat::WebP),
+            extension: "webp".to_string(),
+            options: EncodeOptions::default(),
+            recipe: Recipe::default(),
+            time_completed: None,
+            input_size,
+            output_size,
+        }
+    }
+
+    fn session() -> Session {
+        Session {
+            images: vec![
+                image("queued", ImageList::Queued, 100, 0),
+                image("old", ImageList::Converted, 100, 50),
+                image("upload", ImageList::Uploaded, 100, 0),
+                image("new", ImageList::Converted, 100, 50),
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sessions_round_trip_through_bytes() {
+        assert_eq!(Session::from_bytes(&session().to_bytes()), Some(session()));
+
+        let newer = Session { version: SESSION_VERSION + 1, ..session() };
+        assert_eq!(Session::from_bytes(&newer.to_bytes()), None);
+        assert_eq!(Session::from_bytes(&[1, 2, 3]), None);
+    }
+
+    #[test]
+    fn converted_images_are_evicted_first_and_oldest_first() {
+        let mut session = session();
+        let ids = |images: &[StoredImage]| images.iter().map(|img| img.id.clone()).collect::<Vec<_>>();
+
+        assert!(session.fit_to(500).is_empty());
+        assert_eq!(ids(&session.fit_to(350)), ["old"]);
+        assert_eq!(ids(&session.fit_to(150)), ["new", "upload"]);
+        assert_eq!(ids(&session.images), ["queued"]);
+        assert_eq!(session.byte_keys(), ["queued.in"]);
+    }
+}
@@ -0,0 +1,209 @@
+//! Keeps the image lists in IndexedDB, so reloading the tab picks up where it
+//! left off. What is stored is described in `web_image_converter::session`.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+
+use js_sys::Uint8Array;
+use leptos::{create_rw_signal, RwSignal, SignalGet, SignalSet, SignalUpdate, SignalWith, SignalWithUntracked};
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
+use web_image_converter::session::{input_key, output_key, storage_budget, ImageList, Session, StoredImage, StoredStatus, SESSION_KEY};
+use web_image_converter::upload::decode_upload;
+
+use crate::js::{storageQuota, storeClear, storeDelete, storeGet, storePut};
+use crate::{AppState, DisplayImage, ImageStatus};
+
+pub struct SessionStore {
+    budget: Cell<u64>,
+    written: RefCell<HashSet<String>>, // byte records already in the database
+    saves: Cell<u64>, // a save overtaken by a newer one leaves the session record to it
+    restored: RwSignal<bool>, // nothing is saved before the last session is back
+}
+
+impl Default for SessionStore {
+    fn default() -> Self {
+        SessionStore {
+            budget: Cell::new(u64::MAX),
+            written: Default::default(),
+            saves: Cell::new(0),
+            restored: create_rw_signal(false),
+        }
+    }
+}
+
+/// The record stored under `key`, `None` when there is none.
+pub async fn read_record(key: &str) -> Option<Vec<u8>> {
+    let value = JsFuture::from(storeGet(key)).await.ok()?;
+    value.dyn_into::<Uint8Array>().ok().map(|bytes| bytes.to_vec())
+}
+
+/// Stores `bytes` under `key` in the background.
+pub fn write_record(key: &'static str, bytes: &[u8]) {
+    let bytes = Uint8Array::from(bytes);
+    spawn_local(async move {
+        let _ = JsFuture::from(storePut(key, bytes)).await;
+    });
+}
+
+impl DisplayImage {
+    fn to_stored(&self, list: ImageList) -> StoredImage {
+        let status = match self.status.get() {
+            ImageStatus::Done => StoredStatus::Done,
+            ImageStatus::Failed(err) => StoredStatus::Failed(err),
+            ImageStatus::Cancelled => StoredStatus::Cancelled,
+            ImageStatus::Pending | ImageStatus::Decoding | ImageStatus::Encoding => StoredStatus::Pending,
+        };
+
+        StoredImage {
+            id: self.id.clone(),
+            list,
+            name: self.name.clone(),
+            folder: self.folder.clone(),
+            selected: self.is_selected.get(),
+            status,
+            auto_oriented: self.auto_oriented,
+            format: self.out_filetype,
+            extension: self.out_extension.to_string(),
+            options: self.encode_options,
+            recipe: self.recipe.clone(),
+            time_completed: self.time_completed.clone(),
+            input_size: self.in_file.bytes.len() as u64,
+            output_size: self.encoded().len() as u64,
+        }
+    }
+
+    /// Decodes a stored upload again, `None` if that fails.
+    fn from_stored(stored: StoredImage, input: Vec<u8>, output: Option<Vec<u8>>) -> Option<DisplayImage> {
+        let (in_file, image) = decode_upload(stored.name.clone(), input, stored.auto_oriented).ok()?;
+        let mut img = DisplayImage::new(stored.name, stored.folder, in_file, image, stored.auto_oriented, &mut Vec::new());
+
+        img.id = stored.id;
+        img.is_selected.set(stored.selected);
+        img.status.set(match stored.status {
+            StoredStatus::Pending => ImageStatus::Pending,
+            StoredStatus::Done => ImageStatus::Done,
+            StoredStatus::Failed(err) => ImageStatus::Failed(err),
+            StoredStatus::Cancelled => ImageStatus::Cancelled,
+        });
+        img.out_filetype = stored.format;
+        img.out_extension = stored.format
+            .and_then(|format| format.extensions_str().iter().find(|ext| **ext == stored.extension).copied())
+            .unwrap_or_default();
+        img.encode_options = stored.options;
+        img.recipe = stored.recipe;
+        img.time_completed = stored.time_completed;
+        if img.out_filetype.is_some() {
+            img.out_file = output.map(|encoded| img.output_info(encoded));
+        }
+        Some(img)
+    }
+}
+
+impl AppState {
+    fn lists(&self) -> [(ImageList, RwSignal<Vec<DisplayImage>>); 3] {
+        [
+            (ImageList::Uploaded, self.input_files),
+            (ImageList::Queued, self.queued_files),
+            (ImageList::Converted, self.output_files),
+        ]
+    }
+
+    /// Puts the images of the last session back, then starts saving. Queued
+    /// images start over.
+    pub async fn restore_session(self) {
+        let quota = JsFuture::from(storageQuota()).await.ok().and_then(|quota| quota.as_f64()).unwrap_or(0.0);
+        if quota > 0.0 {
+            self.session.budget.set(storage_budget(quota));
+        }
+
+        let session = read_record(SESSION_KEY).await.and_then(|bytes| Session::from_bytes(&bytes));
+        for stored in session.map(|session| session.images).unwrap_or_default() {
+            let (input_key, output_key) = (stored.input_key(), stored.output_key());
+            let Some(input) = read_record(&input_key).await else {
+                continue;
+            };
+            let output = match &output_key {
+                Some(key) => read_record(key).await,
+                None => None,
+            };
+
+            let list = stored.list;
+            let written = [Some(input_key), output_key.filter(|_| output.is_some())];
+            let Some(img) = DisplayImage::from_stored(stored, input, output) else {
+                continue;
+            };
+            self.session.written.borrow_mut().extend(written.into_iter().flatten());
+            let (_, files) = self.lists().into_iter().find(|(stored_list, _)| *stored_list == list).expect("every list is stored");
+            files.update(|files| files.push(img));
+        }
+
+        self.session.restored.set(true);
+        self.scheduler.wake();
+    }
+
+    /// Stores the lists as they are now. Meant to run in an effect, it reads
+    /// every signal that is stored. Images that don't fit the storage budget
+    /// are left out, see `Session::fit_to`.
+    pub fn save_session(&self) {
+        let store = self.session.clone();
+        if !store.restored.get() {
+            return;
+        }
+
+        let mut session = Session::default();
+        for (list, files) in self.lists() {
+            files.with(|files| session.images.extend(files.iter().map(|img| img.to_stored(list))));
+        }
+        session.fit_to(store.budget.get());
+        let needed: HashSet<String> = session.byte_keys().into_iter().collect();
+
+        // bytes never change once made, so only new ones are written
+        let mut written = store.written.borrow_mut();
+        let mut writes = Vec::new();
+        for (_, files) in self.lists() {
+            files.with_untracked(|files| {
+                for img in files {
+                    for (key, bytes) in [(input_key(&img.id), img.in_file.bytes.as_slice()), (output_key(&img.id), img.encoded())] {
+                        if needed.contains(&key) && written.insert(key.clone()) {
+                            writes.push((key, Uint8Array::from(bytes)));
+                        }
+                    }
+                }
+            });
+        }
+        let deletes: Vec<String> = written.iter().filter(|key| !needed.contains(*key)).cloned().collect();
+        written.retain(|key| needed.contains(key));
+        drop(written);
+
+        let save = store.saves.get() + 1;
+        store.saves.set(save);
+        let record = Uint8Array::from(session.to_bytes().as_slice());
+        spawn_local(async move {
+            for (key, bytes) in writes {
+                if JsFuture::from(storePut(&key, bytes)).await.is_err() {
+                    store.written.borrow_mut().remove(&key); // tried again on the next save
+                }
+            }
+            if store.saves.get() == save {
+                let _ = JsFuture::from(storePut(SESSION_KEY, record)).await;
+            }
+            for key in deletes {
+                let _ = JsFuture::from(storeDelete(&key)).await;
+            }
+        });
+    }
+
+    /// Empties every list and forgets what was stored, settings included.
+    pub fn clear_session(&self) {
+        self.cancel_all();
+        self.input_files.set(Vec::new());
+        self.output_files.set(Vec::new());
+        self.rejected_files.set(Vec::new());
+
+        self.session.written.borrow_mut().clear();
+        spawn_local(async {
+            let _ = JsFuture::from(storeClear()).await;
+        });
+    }
+}
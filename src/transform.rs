@@ -0,0 +1,188 @@
+//! The crop and rotate steps of a recipe, and the crop editor's geometry.
+
+use image::{ColorType, DynamicImage, Rgba, Rgba32FImage};
+
+use crate::options::CropBox;
+
+pub fn crop(img: DynamicImage, crop: CropBox) -> DynamicImage {
+    let rect = crop_rect(img.width(), img.height(), crop);
+    img.crop_imm(rect.x, rect.y, rect.width, rect.height)
+}
+
+/// Rotates clockwise by `degrees`. Quarter turns are exact, other angles grow
+/// the canvas to hold the whole image and fill the uncovered corners with `fill`.
+pub fn rotate(img: DynamicImage, degrees: f32, fill: [u8; 3]) -> DynamicImage {
+    match degrees.rem_euclid(360.0) {
+        0.0 => img,
+        90.0 => img.rotate90(),
+        180.0 => img.rotate180(),
+        270.0 => img.rotate270(),
+        _ => rotate_by(img, degrees, fill),
+    }
+}
+
+/// The size a `width`x`height` image has after `rotate`.
+pub fn rotated_size(width: u32, height: u32, degrees: f32) -> (u32, u32) {
+    match degrees.rem_euclid(360.0) {
+        0.0 | 180.0 => (width, height),
+        90.0 | 270.0 => (height, width),
+        _ => {
+            let (sin, cos) = degrees.to_radians().sin_cos();
+            let (width, height) = (width as f32, height as f32);
+            let side = |length: f32| (length.round() as u32).max(1);
+            (side(width * cos.abs() + height * sin.abs()), side(width * sin.abs() + height * cos.abs()))
+        },
+    }
+}
+
+/// Where `crop` ends up in a `width`x`height` image. A box that fits is used
+/// as it is and one that runs over an edge is moved back inside. When the
+/// image is smaller than the box, the largest box of the same shape is taken
+/// from its center instead.
+pub fn crop_rect(width: u32, height: u32, crop: CropBox) -> CropBox {
+    let (crop_width, crop_height) = (crop.width.max(1), crop.height.max(1));
+
+    if crop_width <= width && crop_height <= height {
+        return CropBox {
+            x: crop.x.min(width - crop_width),
+            y: crop.y.min(height - crop_height),
+            width: crop_width,
+            height: crop_height,
+        };
+    }
+
+    let scale = (f64::from(width) / f64::from(crop_width)).min(f64::from(height) / f64::from(crop_height));
+    let fit_width = ((f64::from(crop_width) * scale).round() as u32).clamp(1, width);
+    let fit_height = ((f64::from(crop_height) * scale).round() as u32).clamp(1, height);
+    CropBox {
+        x: (width - fit_width) / 2,
+        y: (height - fit_height) / 2,
+        width: fit_width,
+        height: fit_height,
+    }
+}
+
+/// The box dragged from `start` to `end` in a `width`x`height` image, held
+/// to `ratio` (width to height) when there is one.
+pub fn drag_crop(start: (f64, f64), end: (f64, f64), ratio: Option<f64>, width: u32, height: u32) -> CropBox {
+    let (right, down) = (end.0 >= start.0, end.1 >= start.1);
+    let (mut crop_width, mut crop_height) = ((end.0 - start.0).abs(), (end.1 - start.1).abs());
+
+    if let Some(ratio) = ratio {
+        // follow whichever way the drag went further, as far as the image allows
+        let room_x = if right { f64::from(width) - start.0 } else { start.0 };
+        let room_y = if down { f64::from(height) - start.1 } else { start.1 };
+        crop_width = crop_width.max(crop_height * ratio).min(room_x).min(room_y * ratio);
+        crop_height = crop_width / ratio;
+    }
+
+    let x = if right { start.0 } else { start.0 - crop_width };
+    let y = if down { start.1 } else { start.1 - crop_height };
+    CropBox {
+        x: x.round().max(0.0) as u32,
+        y: y.round().max(0.0) as u32,
+        width: crop_width.round().max(1.0) as u32,
+        height: crop_height.round().max(1.0) as u32,
+    }
+}
+
+/// Rotates clockwise by any angle, growing the canvas to hold the whole image
+/// and filling the uncovered corners with `fill`.
+fn rotate_by(img: DynamicImage, degrees: f32, fill: [u8; 3]) -> DynamicImage {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (out_width, out_height) = rotated_size(img.width(), img.height(), degrees);
+    let source = img.to_rgba32f();
+    let [r, g, b] = fill.map(|c| f32::from(c) / 255.0);
+    let fill = Rgba([r, g, b, 1.0]);
+
+    let (half_width, half_height) = (img.width() as f32 / 2.0, img.height() as f32 / 2.0);
+    let (out_half_width, out_half_height) = (out_width as f32 / 2.0, out_height as f32 / 2.0);
+    let rotated = Rgba32FImage::from_fn(out_width, out_height, |x, y| {
+        // turn the output pixel's center back counterclockwise to find it in the source
+        let dx = x as f32 + 0.5 - out_half_width;
+        let dy = y as f32 + 0.5 - out_half_height;
+        let source_x = dx * cos + dy * sin + half_width - 0.5;
+        let source_y = -dx * sin + dy * cos + half_height - 0.5;
+        sample_bilinear(&source, source_x, source_y).unwrap_or(fill)
+    });
+
+    // go back to the source precision, gray turns to RGB since the fill may have color
+    let rotated = DynamicImage::ImageRgba32F(rotated);
+    match img.color() {
+        ColorType::L8 | ColorType::Rgb8 => DynamicImage::ImageRgb8(rotated.to_rgb8()),
+        ColorType::La8 | ColorType::Rgba8 => DynamicImage::ImageRgba8(rotated.to_rgba8()),
+        ColorType::L16 | ColorType::Rgb16 => DynamicImage::ImageRgb16(rotated.to_rgb16()),
+        ColorType::La16 | ColorType::Rgba16 => DynamicImage::ImageRgba16(rotated.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(rotated.to_rgb32f()),
+        _ => rotated,
+    }
+}
+
+fn sample_bilinear(img: &Rgba32FImage, x: f32, y: f32) -> Option<Rgba<f32>> {
+    let (max_x, max_y) = (img.width() as f32 - 1.0, img.height() as f32 - 1.0);
+    if !(-0.5..=max_x + 0.5).contains(&x) || !(-0.5..=max_y + 0.5).contains(&y) {
+        return None;
+    }
+
+    let (x, y) = (x.clamp(0.0, max_x), y.clamp(0.0, max_y));
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let (a, b, c, d) = (img.get_pixel(x0, y0).0, img.get_pixel(x1, y0).0, img.get_pixel(x0, y1).0, img.get_pixel(x1, y1).0);
+    let mut pixel = [0.0; 4];
+    for channel in 0..4 {
+        let top = a[channel] + (b[channel] - a[channel]) * fx;
+        let bottom = c[channel] + (d[channel] - c[channel]) * fx;
+        pixel[channel] = top + (bottom - top) * fy;
+    }
+    Some(Rgba(pixel))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb, RgbImage};
+    use super::*;
+
+    fn numbered(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| Rgb([x as u8 * 10, y as u8 * 10, 0])))
+    }
+
+    #[test]
+    fn crops_that_fit_are_kept() {
+        let crop = CropBox { x: 10, y: 20, width: 30, height: 40 };
+        assert_eq!(crop_rect(100, 100, crop), crop);
+        // moved back inside when it runs over the edge
+        assert_eq!(crop_rect(100, 50, crop), CropBox { y: 10, ..crop });
+    }
+
+    #[test]
+    fn small_images_get_a_centered_crop_of_the_same_shape() {
+        let crop = CropBox { x: 500, y: 0, width: 1080, height: 1080 };
+        assert_eq!(crop_rect(800, 600, crop), CropBox { x: 100, y: 0, width: 600, height: 600 });
+    }
+
+    #[test]
+    fn dragged_crops_keep_their_shape() {
+        assert_eq!(drag_crop((10.0, 10.0), (50.0, 30.0), None, 100, 100), CropBox { x: 10, y: 10, width: 40, height: 20 });
+        assert_eq!(drag_crop((50.0, 50.0), (10.0, 40.0), Some(1.0), 100, 100), CropBox { x: 10, y: 10, width: 40, height: 40 });
+        // 16:9 from near the bottom edge runs out of height first
+        assert_eq!(drag_crop((0.0, 82.0), (100.0, 100.0), Some(16.0 / 9.0), 100, 100), CropBox { x: 0, y: 82, width: 32, height: 18 });
+    }
+
+    #[test]
+    fn any_angle_matches_the_quarter_turns() {
+        let img = numbered(6, 4);
+        let fill = [255, 255, 255];
+        assert_eq!(rotate_by(img.clone(), 90.0, fill).to_rgb8(), img.rotate90().to_rgb8());
+        assert_eq!(rotate_by(img.clone(), -90.0, fill).to_rgb8(), img.rotate270().to_rgb8());
+        assert_eq!(rotated_size(6, 4, -90.0), (4, 6));
+    }
+
+    #[test]
+    fn angles_grow_the_canvas_and_fill_the_corners() {
+        let rotated = rotate_by(numbered(10, 10), 45.0, [255, 0, 0]);
+        assert_eq!(rotated.dimensions(), (14, 14));
+        assert_eq!(rotated.get_pixel(0, 0), Rgba([255, 0, 0, 255]));
+    }
+}
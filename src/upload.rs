@@ -0,0 +1,138 @@
+//! Checks and decodes uploaded files before they join the Uploaded list.
+
+use std::fmt::{Display, Formatter};
+use std::io::Cursor;
+
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageReader};
+
+use crate::file_info::FileInfo;
+use crate::metadata::Metadata;
+
+/// Uploads larger than this are turned away before they are even read.
+pub const MAX_UPLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Why an upload didn't make it into the Uploaded list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UploadError {
+    UnknownFormat,
+    Decode(String),
+    Archive(String),
+    TooLarge { size: u64, max: u64 },
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::UnknownFormat => write!(f, "Not a supported image format"),
+            UploadError::Decode(msg) => write!(f, "Could not decode the image: {msg}"),
+            UploadError::Archive(msg) => write!(f, "Could not unpack the archive: {msg}"),
+            UploadError::TooLarge { size, max } => {
+                write!(f, "{} MiB is over the {} MiB upload limit", size / (1024 * 1024), max / (1024 * 1024))
+            },
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+pub fn check_upload_size(size: u64) -> Result<(), UploadError> {
+    if size > MAX_UPLOAD_BYTES {
+        return Err(UploadError::TooLarge { size, max: MAX_UPLOAD_BYTES });
+    }
+    Ok(())
+}
+
+/// Works out the format from the file's contents and decodes it. With
+/// `auto_orient` the pixels are turned upright as the EXIF orientation says,
+/// and the tag in the kept EXIF is reset so viewers don't turn them again.
+pub fn decode_upload(name: String, bytes: Vec<u8>, auto_orient: bool) -> Result<(FileInfo, DynamicImage), UploadError> {
+    check_upload_size(bytes.len() as u64)?;
+    let decode_error = |err: ImageError| UploadError::Decode(err.to_string());
+
+    let reader = ImageReader::new(Cursor::new(bytes.as_slice()))
+        .with_guessed_format()
+        .expect("reading from memory can't fail");
+    let file_type = reader.format().ok_or(UploadError::UnknownFormat)?;
+    let mut decoder = reader.into_decoder().map_err(decode_error)?;
+    let (width, height) = decoder.dimensions();
+    let color = decoder.original_color_type();
+    // a broken orientation tag isn't worth rejecting the image over
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+
+    let mut metadata = Metadata::read(&bytes);
+    if auto_orient {
+        img.apply_orientation(orientation);
+        metadata.reset_orientation();
+    }
+    let info = FileInfo { name, file_type, metadata, bytes, width, height, color };
+    Ok((info, img))
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::experimental::Writer;
+    use exif::{Field, In, Tag, Value};
+    use image::{ImageFormat, Rgb, RgbImage};
+    use super::*;
+    use crate::convert::convert_image;
+    use crate::metadata::write_metadata;
+    use crate::options::{EncodeOptions, ExifMode, MetadataOptions};
+
+    fn png() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([1, 2, 3])))
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn decodes_images() {
+        let (info, img) = decode_upload("a.png".to_string(), png(), true).unwrap();
+        assert_eq!((info.file_type, info.width, info.size()), (ImageFormat::Png, 4, png().len()));
+        assert_eq!(img.to_rgb8().get_pixel(3, 3), &Rgb([1, 2, 3]));
+    }
+
+    // a 4x2 JPEG tagged to be turned a quarter clockwise
+    fn rotated_jpeg() -> Vec<u8> {
+        let field = Field { tag: Tag::Orientation, ifd_num: In::PRIMARY, value: Value::Short(vec![6]) };
+        let mut writer = Writer::new();
+        writer.push_field(&field);
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+
+        let jpeg = convert_image(DynamicImage::ImageRgb8(RgbImage::new(4, 2)), ImageFormat::Jpeg, &EncodeOptions::default(), None).unwrap();
+        let metadata = Metadata { exif: Some(exif.into_inner()), ..Default::default() };
+        write_metadata(jpeg, ImageFormat::Jpeg, &metadata, &MetadataOptions { exif: ExifMode::Keep, ..Default::default() })
+    }
+
+    #[test]
+    fn applies_exif_orientation() {
+        let (info, img) = decode_upload("a.jpg".to_string(), rotated_jpeg(), true).unwrap();
+        assert_eq!((img.width(), img.height()), (2, 4));
+        assert_eq!(Orientation::from_exif_chunk(&info.metadata.exif.unwrap()), Some(Orientation::NoTransforms));
+
+        let (info, img) = decode_upload("a.jpg".to_string(), rotated_jpeg(), false).unwrap();
+        assert_eq!((img.width(), img.height()), (4, 2));
+        assert_eq!(Orientation::from_exif_chunk(&info.metadata.exif.unwrap()), Some(Orientation::Rotate90));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert_eq!(decode_upload("a.pdf".to_string(), b"%PDF-1.7 not an image".to_vec(), true).unwrap_err(), UploadError::UnknownFormat);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let png = png();
+        assert!(matches!(decode_upload("a.png".to_string(), png[..png.len() / 2].to_vec(), true), Err(UploadError::Decode(_))));
+    }
+
+    #[test]
+    fn rejects_oversized_files() {
+        assert!(check_upload_size(MAX_UPLOAD_BYTES).is_ok());
+        assert_eq!(check_upload_size(MAX_UPLOAD_BYTES + 1), Err(UploadError::TooLarge { size: MAX_UPLOAD_BYTES + 1, max: MAX_UPLOAD_BYTES }));
+    }
+}
@@ -0,0 +1,972 @@
+//! Lossy WebP. The `image` crate only writes lossless (VP8L) WebP, so this is
+//! a small VP8 key frame encoder of our own. Every macroblock is predicted as
+//! a whole with one of the four 16x16 (and 8x8 chroma) modes, the residue goes
+//! through the usual DCT and Walsh-Hadamard transforms, and the tokens are
+//! coded with the default probabilities. Alpha is stored losslessly in an
+//! ALPH chunk next to the VP8 frame.
+
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ExtendedColorType, GrayImage, ImageEncoder, RgbaImage};
+
+use crate::error::ConversionError;
+use crate::metadata::webp_chunk;
+
+/// VP8 stores each side in 14 bits.
+pub const MAX_SIDE: u32 = 16383;
+
+/// Encodes `img` as a lossy WebP file. `quality` goes from 0 (smallest) to
+/// 100 (closest to the original), like libwebp's.
+pub fn encode_lossy_webp(img: &DynamicImage, quality: u8, icc: Option<&[u8]>) -> Result<Vec<u8>, ConversionError> {
+    let (width, height) = (img.width(), img.height());
+    if width > MAX_SIDE || height > MAX_SIDE {
+        return Err(ConversionError::DimensionLimitsExceeded { width, height, max: MAX_SIDE });
+    }
+    if width == 0 || height == 0 {
+        return Err(ConversionError::Encoder("can't encode an empty image".to_string()));
+    }
+
+    let rgba = img.to_rgba8();
+    let transparent = img.color().has_alpha() && rgba.pixels().any(|p| p[3] != 255);
+
+    let mut chunks = Vec::new();
+    if transparent || icc.is_some() {
+        let flags = if icc.is_some() { 0x20 } else { 0 } | if transparent { 0x10 } else { 0 };
+        let mut vp8x = vec![flags, 0, 0, 0];
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        chunks.extend(webp_chunk(b"VP8X", &vp8x));
+        if let Some(icc) = icc {
+            chunks.extend(webp_chunk(b"ICCP", icc));
+        }
+        if transparent {
+            chunks.extend(webp_chunk(b"ALPH", &alpha_data(&rgba)?));
+        }
+    }
+    chunks.extend(webp_chunk(b"VP8 ", &encode_frame(&rgba, quality)?));
+
+    Ok([b"RIFF".as_slice(), &(chunks.len() as u32 + 4).to_le_bytes(), b"WEBP", &chunks].concat())
+}
+
+// The alpha plane as a VP8L image stream in the green channel, which is a
+// lossless WebP file minus its RIFF and VP8L headers.
+fn alpha_data(rgba: &RgbaImage) -> Result<Vec<u8>, ConversionError> {
+    let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| image::Luma([rgba.get_pixel(x, y)[3]]));
+    let mut webp = Vec::new();
+    WebPEncoder::new_lossless(&mut webp).write_image(alpha.as_raw(), alpha.width(), alpha.height(), ExtendedColorType::L8)?;
+
+    const STREAM_START: usize = 12 + 8 + 5;
+    if webp.len() < STREAM_START || &webp[12..16] != b"VP8L" {
+        return Err(ConversionError::Encoder("unexpected lossless WebP layout for the alpha channel".to_string()));
+    }
+    let size = u32::from_le_bytes([webp[16], webp[17], webp[18], webp[19]]) as usize;
+
+    let mut data = vec![1]; // no filtering, lossless compression
+    data.extend_from_slice(&webp[STREAM_START..20 + size]);
+    Ok(data)
+}
+
+// Maps the 0-100 quality onto the 0-127 quantizer index, 100 being the finest.
+fn quantizer_index(quality: u8) -> usize {
+    127 * (100 - usize::from(quality.min(100))) / 100
+}
+
+fn encode_frame(rgba: &RgbaImage, quality: u8) -> Result<Vec<u8>, ConversionError> {
+    let (width, height) = rgba.dimensions();
+    let mb_width = width.div_ceil(16) as usize;
+    let mb_height = height.div_ceil(16) as usize;
+
+    let source = YuvImage::from_rgba(rgba, mb_width, mb_height);
+    let mut recon = YuvImage::blank(mb_width, mb_height);
+    let q = quantizer_index(quality);
+    let quant = Quantizers::new(q);
+
+    let mut tokens = BoolEncoder::new();
+    let mut top = vec![[0u8; 9]; mb_width];
+    let mut macroblocks = Vec::with_capacity(mb_width * mb_height);
+    for mby in 0..mb_height {
+        let mut left = [0u8; 9];
+        for (mbx, top) in top.iter_mut().enumerate() {
+            let mb = encode_macroblock(&source, &mut recon, &quant, mbx, mby);
+            if mb.modes.skip {
+                *top = [0; 9];
+                left = [0; 9];
+            } else {
+                write_macroblock_tokens(&mut tokens, &mb.levels, top, &mut left);
+            }
+            macroblocks.push(mb.modes);
+        }
+    }
+
+    let skipped = macroblocks.iter().filter(|modes| modes.skip).count();
+    let prob_skip_false = if skipped == 0 {
+        None
+    } else {
+        Some((256 * (macroblocks.len() - skipped) / macroblocks.len()).clamp(1, 255) as u8)
+    };
+
+    let mut header = BoolEncoder::new();
+    header.put_literal(0, 1); // color space
+    header.put_literal(0, 1); // clamping required
+    header.put_flag(false); // segmentation
+    header.put_flag(false); // normal loop filter
+    header.put_literal(loop_filter_level(q), 6);
+    header.put_literal(0, 3); // sharpness
+    header.put_flag(false); // loop filter deltas
+    header.put_literal(0, 2); // one token partition
+    header.put_literal(q as u32, 7);
+    for _ in 0..5 {
+        header.put_flag(false); // no quantizer deltas
+    }
+    header.put_flag(false); // refresh entropy probabilities
+    for probs in COEFF_UPDATE_PROBS.iter().flatten().flatten().flatten() {
+        header.put(false, *probs);
+    }
+    header.put_flag(prob_skip_false.is_some());
+    if let Some(prob) = prob_skip_false {
+        header.put_literal(u32::from(prob), 8);
+    }
+    for modes in &macroblocks {
+        if let Some(prob) = prob_skip_false {
+            header.put(modes.skip, prob);
+        }
+        write_luma_mode(&mut header, modes.luma);
+        write_chroma_mode(&mut header, modes.chroma);
+    }
+
+    let first_partition = header.finish();
+    if first_partition.len() >= 1 << 19 {
+        return Err(ConversionError::Encoder("too many macroblocks for a lossy WebP frame".to_string()));
+    }
+
+    // key frame, version 0, shown, then the first partition's size
+    let tag = (first_partition.len() as u32) << 5 | 1 << 4;
+    let mut frame = tag.to_le_bytes()[..3].to_vec();
+    frame.extend_from_slice(&[0x9d, 0x01, 0x2a]);
+    frame.extend_from_slice(&(width as u16).to_le_bytes());
+    frame.extend_from_slice(&(height as u16).to_le_bytes());
+    frame.extend(first_partition);
+    frame.extend(tokens.finish());
+    Ok(frame)
+}
+
+// Smooths block edges more as the quantizer gets coarser.
+fn loop_filter_level(q: usize) -> u32 {
+    (AC_QUANT[q] as u32 * 3 / 8).min(63)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Dc,
+    Vertical,
+    Horizontal,
+    TrueMotion,
+}
+
+const MODES: [Mode; 4] = [Mode::Dc, Mode::Vertical, Mode::Horizontal, Mode::TrueMotion];
+
+struct Modes {
+    luma: Mode,
+    chroma: Mode,
+    skip: bool,
+}
+
+// Quantized levels of one macroblock, each 4x4 block in raster order.
+#[derive(Default)]
+struct Levels {
+    y2: [i32; 16],
+    y: [[i32; 16]; 16],
+    u: [[i32; 16]; 4],
+    v: [[i32; 16]; 4],
+}
+
+struct Macroblock {
+    modes: Modes,
+    levels: Levels,
+}
+
+struct Plane {
+    data: Vec<u8>,
+    stride: usize,
+}
+
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Plane { data: vec![0; width * height], stride: width }
+    }
+
+    fn at(&self, x: usize, y: usize) -> u8 {
+        self.data[y * self.stride + x]
+    }
+
+    fn set(&mut self, x: usize, y: usize, value: u8) {
+        self.data[y * self.stride + x] = value;
+    }
+}
+
+// The frame in 4:2:0 YCbCr, padded to whole macroblocks.
+struct YuvImage {
+    y: Plane,
+    u: Plane,
+    v: Plane,
+}
+
+impl YuvImage {
+    fn blank(mb_width: usize, mb_height: usize) -> Self {
+        YuvImage {
+            y: Plane::new(mb_width * 16, mb_height * 16),
+            u: Plane::new(mb_width * 8, mb_height * 8),
+            v: Plane::new(mb_width * 8, mb_height * 8),
+        }
+    }
+
+    // libwebp's BT.601 conversion. The padding repeats the last row and column.
+    fn from_rgba(rgba: &RgbaImage, mb_width: usize, mb_height: usize) -> Self {
+        let mut yuv = Self::blank(mb_width, mb_height);
+        let (last_x, last_y) = (rgba.width() - 1, rgba.height() - 1);
+        let rgb = |x: usize, y: usize| {
+            let pixel = rgba.get_pixel((x as u32).min(last_x), (y as u32).min(last_y));
+            [0, 1, 2].map(|c| i32::from(pixel[c]))
+        };
+
+        for y in 0..mb_height * 16 {
+            for x in 0..mb_width * 16 {
+                let [r, g, b] = rgb(x, y);
+                let luma = (16839 * r + 33059 * g + 6420 * b + (1 << 15) + (16 << 16)) >> 16;
+                yuv.y.set(x, y, luma as u8);
+            }
+        }
+
+        for y in 0..mb_height * 8 {
+            for x in 0..mb_width * 8 {
+                let [r, g, b] = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .map(|(dx, dy)| rgb(2 * x + dx, 2 * y + dy))
+                    .into_iter()
+                    .fold([0; 3], |sum, pixel| [0, 1, 2].map(|c| sum[c] + pixel[c]));
+                let chroma = |value: i32| ((value + (1 << 17) + (128 << 18)) >> 18).clamp(0, 255) as u8;
+                yuv.u.set(x, y, chroma(-9719 * r - 19081 * g + 28800 * b));
+                yuv.v.set(x, y, chroma(28800 * r - 24116 * g - 4684 * b));
+            }
+        }
+
+        yuv
+    }
+}
+
+struct Quantizers {
+    y_ac: i32,
+    y2_dc: i32,
+    y2_ac: i32,
+    uv_dc: i32,
+    uv_ac: i32,
+}
+
+impl Quantizers {
+    // The step sizes a decoder derives from the index, section 14.1 of RFC 6386.
+    fn new(q: usize) -> Self {
+        Quantizers {
+            y_ac: AC_QUANT[q],
+            y2_dc: DC_QUANT[q] * 2,
+            y2_ac: (AC_QUANT[q] * 155 / 100).max(8),
+            uv_dc: DC_QUANT[q].min(132),
+            uv_ac: AC_QUANT[q],
+        }
+    }
+}
+
+// The pixels a decoder predicts a macroblock from: the reconstructed row
+// above and column to the left, or 127 above the first row and 129 left of
+// the first column.
+struct Edges {
+    above: [u8; 16],
+    left: [u8; 16],
+    corner: u8,
+    has_above: bool,
+    has_left: bool,
+}
+
+impl Edges {
+    fn new(recon: &Plane, x0: usize, y0: usize, size: usize) -> Self {
+        let mut above = [127; 16];
+        let mut left = [129; 16];
+        if y0 > 0 {
+            (0..size).for_each(|i| above[i] = recon.at(x0 + i, y0 - 1));
+        }
+        if x0 > 0 {
+            (0..size).for_each(|i| left[i] = recon.at(x0 - 1, y0 + i));
+        }
+        let corner = match (x0, y0) {
+            (_, 0) => 127,
+            (0, _) => 129,
+            _ => recon.at(x0 - 1, y0 - 1),
+        };
+        Edges { above, left, corner, has_above: y0 > 0, has_left: x0 > 0 }
+    }
+
+    // `size` x `size` pixels, row by row
+    fn predict(&self, mode: Mode, size: usize) -> [u8; 256] {
+        let shift = size.trailing_zeros();
+        let sum = |edge: &[u8; 16]| edge[..size].iter().map(|&p| u32::from(p)).sum::<u32>();
+        let dc = match (self.has_above, self.has_left) {
+            (true, true) => (sum(&self.above) + sum(&self.left) + size as u32) >> (shift + 1),
+            (true, false) => (sum(&self.above) + size as u32 / 2) >> shift,
+            (false, true) => (sum(&self.left) + size as u32 / 2) >> shift,
+            (false, false) => 128,
+        } as u8;
+
+        let mut prediction = [0; 256];
+        for y in 0..size {
+            for x in 0..size {
+                prediction[y * size + x] = match mode {
+                    Mode::Dc => dc,
+                    Mode::Vertical => self.above[x],
+                    Mode::Horizontal => self.left[y],
+                    Mode::TrueMotion => {
+                        let value = i32::from(self.left[y]) + i32::from(self.above[x]) - i32::from(self.corner);
+                        value.clamp(0, 255) as u8
+                    },
+                };
+            }
+        }
+        prediction
+    }
+}
+
+fn distortion(source: &Plane, x0: usize, y0: usize, prediction: &[u8; 256], size: usize) -> u64 {
+    let mut sum = 0;
+    for y in 0..size {
+        for x in 0..size {
+            let diff = i64::from(source.at(x0 + x, y0 + y)) - i64::from(prediction[y * size + x]);
+            sum += (diff * diff) as u64;
+        }
+    }
+    sum
+}
+
+// The difference between the source and the prediction in the 4x4 block
+// `block` of a `size` wide macroblock.
+fn residue(source: &Plane, x0: usize, y0: usize, prediction: &[u8; 256], size: usize, block: usize) -> [i32; 16] {
+    let (bx, by) = (block % (size / 4) * 4, block / (size / 4) * 4);
+    std::array::from_fn(|i| {
+        let (x, y) = (bx + i % 4, by + i / 4);
+        i32::from(source.at(x0 + x, y0 + y)) - i32::from(prediction[y * size + x])
+    })
+}
+
+fn reconstruct(recon: &mut Plane, x0: usize, y0: usize, prediction: &[u8; 256], size: usize, block: usize, residue: &[i32; 16]) {
+    let (bx, by) = (block % (size / 4) * 4, block / (size / 4) * 4);
+    for (i, &diff) in residue.iter().enumerate() {
+        let (x, y) = (bx + i % 4, by + i / 4);
+        let value = i32::from(prediction[y * size + x]) + diff;
+        recon.set(x0 + x, y0 + y, value.clamp(0, 255) as u8);
+    }
+}
+
+// Predicts, transforms and quantizes one macroblock, and writes what a
+// decoder will make of it into `recon` for the macroblocks after it.
+fn encode_macroblock(source: &YuvImage, recon: &mut YuvImage, quant: &Quantizers, mbx: usize, mby: usize) -> Macroblock {
+    let mut levels = Levels::default();
+
+    let (x0, y0) = (mbx * 16, mby * 16);
+    let edges = Edges::new(&recon.y, x0, y0, 16);
+    let luma = MODES
+        .into_iter()
+        .min_by_key(|&mode| distortion(&source.y, x0, y0, &edges.predict(mode, 16), 16))
+        .unwrap_or(Mode::Dc);
+    let prediction = edges.predict(luma, 16);
+
+    // the DC of every luma block goes through the second order (Y2) block
+    let mut dc = [0; 16];
+    for (block, block_levels) in levels.y.iter_mut().enumerate() {
+        let coeffs = fdct(&residue(&source.y, x0, y0, &prediction, 16, block));
+        dc[block] = coeffs[0];
+        *block_levels = quantize(&coeffs, quant.y_ac, quant.y_ac);
+        block_levels[0] = 0;
+    }
+    levels.y2 = quantize(&fwht(&dc), quant.y2_dc, quant.y2_ac);
+
+    let dc = iwht(&dequantize(&levels.y2, quant.y2_dc, quant.y2_ac));
+    for (block, block_levels) in levels.y.iter().enumerate() {
+        let mut coeffs = dequantize(block_levels, 0, quant.y_ac);
+        coeffs[0] = dc[block];
+        reconstruct(&mut recon.y, x0, y0, &prediction, 16, block, &idct(&coeffs));
+    }
+
+    // U and V share one mode
+    let (x0, y0) = (mbx * 8, mby * 8);
+    let u_edges = Edges::new(&recon.u, x0, y0, 8);
+    let v_edges = Edges::new(&recon.v, x0, y0, 8);
+    let chroma = MODES
+        .into_iter()
+        .min_by_key(|&mode| {
+            distortion(&source.u, x0, y0, &u_edges.predict(mode, 8), 8)
+                + distortion(&source.v, x0, y0, &v_edges.predict(mode, 8), 8)
+        })
+        .unwrap_or(Mode::Dc);
+
+    for (source, recon, edges, plane_levels) in [
+        (&source.u, &mut recon.u, &u_edges, &mut levels.u),
+        (&source.v, &mut recon.v, &v_edges, &mut levels.v),
+    ] {
+        let prediction = edges.predict(chroma, 8);
+        for (block, block_levels) in plane_levels.iter_mut().enumerate() {
+            let coeffs = fdct(&residue(source, x0, y0, &prediction, 8, block));
+            *block_levels = quantize(&coeffs, quant.uv_dc, quant.uv_ac);
+            let coeffs = dequantize(block_levels, quant.uv_dc, quant.uv_ac);
+            reconstruct(recon, x0, y0, &prediction, 8, block, &idct(&coeffs));
+        }
+    }
+
+    let skip = levels.y2.iter()
+        .chain(levels.y.iter().flatten())
+        .chain(levels.u.iter().flatten())
+        .chain(levels.v.iter().flatten())
+        .all(|&level| level == 0);
+    Macroblock { modes: Modes { luma, chroma, skip }, levels }
+}
+
+// The largest level a DCT_CAT6 token holds.
+const MAX_LEVEL: i32 = 67 + 2047;
+
+fn quantize(coeffs: &[i32; 16], dc_step: i32, ac_step: i32) -> [i32; 16] {
+    std::array::from_fn(|i| {
+        let step = if i == 0 { dc_step } else { ac_step };
+        let level = ((coeffs[i].abs() + step / 2) / step).min(MAX_LEVEL);
+        level * coeffs[i].signum()
+    })
+}
+
+fn dequantize(levels: &[i32; 16], dc_step: i32, ac_step: i32) -> [i32; 16] {
+    std::array::from_fn(|i| levels[i] * if i == 0 { dc_step } else { ac_step })
+}
+
+// libvpx's forward DCT, the inverse of the decoder's `idct` below.
+fn fdct(input: &[i32; 16]) -> [i32; 16] {
+    let mut rows = [0; 16];
+    for (row, out) in input.chunks_exact(4).zip(rows.chunks_exact_mut(4)) {
+        let a1 = (row[0] + row[3]) * 8;
+        let b1 = (row[1] + row[2]) * 8;
+        let c1 = (row[1] - row[2]) * 8;
+        let d1 = (row[0] - row[3]) * 8;
+        out[0] = a1 + b1;
+        out[2] = a1 - b1;
+        out[1] = (c1 * 2217 + d1 * 5352 + 14500) >> 12;
+        out[3] = (d1 * 2217 - c1 * 5352 + 7500) >> 12;
+    }
+
+    let mut output = [0; 16];
+    for i in 0..4 {
+        let a1 = rows[i] + rows[12 + i];
+        let b1 = rows[4 + i] + rows[8 + i];
+        let c1 = rows[4 + i] - rows[8 + i];
+        let d1 = rows[i] - rows[12 + i];
+        output[i] = (a1 + b1 + 7) >> 4;
+        output[8 + i] = (a1 - b1 + 7) >> 4;
+        output[4 + i] = ((c1 * 2217 + d1 * 5352 + 12000) >> 16) + i32::from(d1 != 0);
+        output[12 + i] = (d1 * 2217 - c1 * 5352 + 51000) >> 16;
+    }
+    output
+}
+
+// Section 14.3 of RFC 6386.
+fn idct(input: &[i32; 16]) -> [i32; 16] {
+    const C1: i64 = 20091;
+    const C2: i64 = 35468;
+    let at = |block: &[i32; 16], i: usize| i64::from(block[i]);
+
+    let mut columns = [0; 16];
+    for i in 0..4 {
+        let a1 = at(input, i) + at(input, 8 + i);
+        let b1 = at(input, i) - at(input, 8 + i);
+        let c1 = ((at(input, 4 + i) * C2) >> 16) - (at(input, 12 + i) + ((at(input, 12 + i) * C1) >> 16));
+        let d1 = (at(input, 4 + i) + ((at(input, 4 + i) * C1) >> 16)) + ((at(input, 12 + i) * C2) >> 16);
+        columns[i] = (a1 + d1) as i32;
+        columns[4 + i] = (b1 + c1) as i32;
+        columns[8 + i] = (b1 - c1) as i32;
+        columns[12 + i] = (a1 - d1) as i32;
+    }
+
+    let mut output = [0; 16];
+    for i in (0..16).step_by(4) {
+        let a1 = at(&columns, i) + at(&columns, i + 2);
+        let b1 = at(&columns, i) - at(&columns, i + 2);
+        let c1 = ((at(&columns, i + 1) * C2) >> 16) - (at(&columns, i + 3) + ((at(&columns, i + 3) * C1) >> 16));
+        let d1 = (at(&columns, i + 1) + ((at(&columns, i + 1) * C1) >> 16)) + ((at(&columns, i + 3) * C2) >> 16);
+        output[i] = ((a1 + d1 + 4) >> 3) as i32;
+        output[i + 1] = ((b1 + c1 + 4) >> 3) as i32;
+        output[i + 2] = ((b1 - c1 + 4) >> 3) as i32;
+        output[i + 3] = ((a1 - d1 + 4) >> 3) as i32;
+    }
+    output
+}
+
+// libvpx's forward Walsh-Hadamard transform of the 16 luma DC coefficients.
+fn fwht(input: &[i32; 16]) -> [i32; 16] {
+    let mut rows = [0; 16];
+    for (row, out) in input.chunks_exact(4).zip(rows.chunks_exact_mut(4)) {
+        let a1 = (row[0] + row[2]) * 4;
+        let d1 = (row[1] + row[3]) * 4;
+        let c1 = (row[1] - row[3]) * 4;
+        let b1 = (row[0] - row[2]) * 4;
+        out[0] = a1 + d1 + i32::from(a1 != 0);
+        out[1] = b1 + c1;
+        out[2] = b1 - c1;
+        out[3] = a1 - d1;
+    }
+
+    let mut output = [0; 16];
+    for i in 0..4 {
+        let a1 = rows[i] + rows[8 + i];
+        let d1 = rows[4 + i] + rows[12 + i];
+        let c1 = rows[4 + i] - rows[12 + i];
+        let b1 = rows[i] - rows[8 + i];
+        for (at, value) in [(i, a1 + d1), (4 + i, b1 + c1), (8 + i, b1 - c1), (12 + i, a1 - d1)] {
+            output[at] = (value + i32::from(value < 0) + 3) >> 3;
+        }
+    }
+    output
+}
+
+// Section 14.3 of RFC 6386.
+fn iwht(input: &[i32; 16]) -> [i32; 16] {
+    let mut columns = [0; 16];
+    for i in 0..4 {
+        let a1 = input[i] + input[12 + i];
+        let b1 = input[4 + i] + input[8 + i];
+        let c1 = input[4 + i] - input[8 + i];
+        let d1 = input[i] - input[12 + i];
+        columns[i] = a1 + b1;
+        columns[4 + i] = c1 + d1;
+        columns[8 + i] = a1 - b1;
+        columns[12 + i] = d1 - c1;
+    }
+
+    let mut output = [0; 16];
+    for (row, out) in columns.chunks_exact(4).zip(output.chunks_exact_mut(4)) {
+        let a1 = row[0] + row[3];
+        let b1 = row[1] + row[2];
+        let c1 = row[1] - row[2];
+        let d1 = row[0] - row[3];
+        out[0] = (a1 + b1 + 3) >> 3;
+        out[1] = (c1 + d1 + 3) >> 3;
+        out[2] = (a1 - b1 + 3) >> 3;
+        out[3] = (d1 - c1 + 3) >> 3;
+    }
+    output
+}
+
+// Writes the macroblock's tokens in the order a decoder reads them. `top` and
+// `left` say which neighbouring blocks had coefficients: Y2 first, then the
+// four luma columns or rows, then two each for U and V.
+fn write_macroblock_tokens(encoder: &mut BoolEncoder, levels: &Levels, top: &mut [u8; 9], left: &mut [u8; 9]) {
+    let nonzero = write_block(encoder, &COEFF_PROBS[1], &levels.y2, 0, top[0] + left[0]);
+    (top[0], left[0]) = (nonzero, nonzero);
+
+    for (block, block_levels) in levels.y.iter().enumerate() {
+        let (x, y) = (1 + block % 4, 1 + block / 4);
+        let nonzero = write_block(encoder, &COEFF_PROBS[0], block_levels, 1, top[x] + left[y]);
+        (top[x], left[y]) = (nonzero, nonzero);
+    }
+
+    for (first, plane_levels) in [(5, &levels.u), (7, &levels.v)] {
+        for (block, block_levels) in plane_levels.iter().enumerate() {
+            let (x, y) = (first + block % 2, first + block / 2);
+            let nonzero = write_block(encoder, &COEFF_PROBS[2], block_levels, 0, top[x] + left[y]);
+            (top[x], left[y]) = (nonzero, nonzero);
+        }
+    }
+}
+
+// Codes the levels from `first` on in zigzag order, ending with an end of
+// block token unless the last coefficient is set. Returns 1 if any level was
+// written.
+fn write_block(encoder: &mut BoolEncoder, probs: &[[[u8; 11]; 3]; 8], levels: &[i32; 16], first: usize, context: u8) -> u8 {
+    let mut context = usize::from(context);
+    let Some(last) = (first..16).rev().find(|&i| levels[ZIGZAG[i]] != 0) else {
+        encoder.put(false, probs[BANDS[first]][context][0]);
+        return 0;
+    };
+
+    let mut after_zero = false;
+    for i in first..=last {
+        let probs = &probs[BANDS[i]][context];
+        let level = levels[ZIGZAG[i]];
+        // a zero can't be followed by the end of the block, so that branch is skipped
+        if !after_zero {
+            encoder.put(true, probs[0]);
+        }
+        if level == 0 {
+            encoder.put(false, probs[1]);
+            (context, after_zero) = (0, true);
+            continue;
+        }
+
+        encoder.put(true, probs[1]);
+        write_token(encoder, probs, level.unsigned_abs());
+        encoder.put_flag(level < 0);
+        (context, after_zero) = (if level.abs() == 1 { 1 } else { 2 }, false);
+    }
+
+    if last < 15 {
+        encoder.put(false, probs[BANDS[last + 1]][context][0]);
+    }
+    1
+}
+
+// The token tree below the "not zero" branch, section 13.2 of RFC 6386.
+fn write_token(encoder: &mut BoolEncoder, probs: &[u8; 11], value: u32) {
+    if value == 1 {
+        encoder.put(false, probs[2]);
+        return;
+    }
+    encoder.put(true, probs[2]);
+
+    match value {
+        2 => {
+            encoder.put(false, probs[3]);
+            encoder.put(false, probs[4]);
+        },
+        3 | 4 => {
+            encoder.put(false, probs[3]);
+            encoder.put(true, probs[4]);
+            encoder.put(value == 4, probs[5]);
+        },
+        _ => {
+            encoder.put(true, probs[3]);
+            let category = CATEGORIES.iter().rposition(|&(base, _)| value >= base).unwrap_or(0);
+            match category {
+                0 | 1 => {
+                    encoder.put(false, probs[6]);
+                    encoder.put(category == 1, probs[7]);
+                },
+                2 | 3 => {
+                    encoder.put(true, probs[6]);
+                    encoder.put(false, probs[8]);
+                    encoder.put(category == 3, probs[9]);
+                },
+                _ => {
+                    encoder.put(true, probs[6]);
+                    encoder.put(true, probs[8]);
+                    encoder.put(category == 5, probs[10]);
+                },
+            }
+
+            let (base, extra_probs) = CATEGORIES[category];
+            let extra = value - base;
+            for (i, &prob) in extra_probs.iter().enumerate() {
+                let bit = extra_probs.len() - 1 - i;
+                encoder.put((extra >> bit) & 1 == 1, prob);
+            }
+        },
+    }
+}
+
+// The key frame trees with their fixed probabilities, section 11.2 of RFC 6386.
+fn write_luma_mode(encoder: &mut BoolEncoder, mode: Mode) {
+    encoder.put(true, 145); // not split into 4x4 blocks
+    let (first, second) = match mode {
+        Mode::Dc => (false, false),
+        Mode::Vertical => (false, true),
+        Mode::Horizontal => (true, false),
+        Mode::TrueMotion => (true, true),
+    };
+    encoder.put(first, 156);
+    encoder.put(second, if first { 128 } else { 163 });
+}
+
+fn write_chroma_mode(encoder: &mut BoolEncoder, mode: Mode) {
+    let bits: &[(bool, u8)] = match mode {
+        Mode::Dc => &[(false, 142)],
+        Mode::Vertical => &[(true, 142), (false, 114)],
+        Mode::Horizontal => &[(true, 142), (true, 114), (false, 183)],
+        Mode::TrueMotion => &[(true, 142), (true, 114), (true, 183)],
+    };
+    bits.iter().for_each(|&(bit, prob)| encoder.put(bit, prob));
+}
+
+// The boolean entropy encoder of section 7.3 of RFC 6386.
+struct BoolEncoder {
+    data: Vec<u8>,
+    range: u32,
+    bottom: u32,
+    bit_count: u32,
+}
+
+impl BoolEncoder {
+    fn new() -> Self {
+        BoolEncoder { data: Vec::new(), range: 255, bottom: 0, bit_count: 24 }
+    }
+
+    // `prob` is the chance of a false bit, out of 256
+    fn put(&mut self, bit: bool, prob: u8) {
+        let split = 1 + (((self.range - 1) * u32::from(prob)) >> 8);
+        if bit {
+            self.bottom += split;
+            self.range -= split;
+        } else {
+            self.range = split;
+        }
+
+        while self.range < 128 {
+            self.range <<= 1;
+            if self.bottom & (1 << 31) != 0 {
+                self.carry();
+            }
+            self.bottom <<= 1;
+            self.bit_count -= 1;
+            if self.bit_count == 0 {
+                self.data.push((self.bottom >> 24) as u8);
+                self.bottom &= (1 << 24) - 1;
+                self.bit_count = 8;
+            }
+        }
+    }
+
+    fn put_flag(&mut self, bit: bool) {
+        self.put(bit, 128);
+    }
+
+    fn put_literal(&mut self, value: u32, bits: u32) {
+        for bit in (0..bits).rev() {
+            self.put_flag((value >> bit) & 1 == 1);
+        }
+    }
+
+    fn carry(&mut self) {
+        for byte in self.data.iter_mut().rev() {
+            if *byte == 255 {
+                *byte = 0;
+            } else {
+                *byte += 1;
+                return;
+            }
+        }
+    }
+
+    // pushes out whatever is still pending, like libvpx does
+    fn finish(mut self) -> Vec<u8> {
+        for _ in 0..32 {
+            self.put_flag(false);
+        }
+        self.data
+    }
+}
+
+const ZIGZAG: [usize; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
+const BANDS: [usize; 16] = [0, 1, 2, 3, 6, 4, 5, 6, 6, 6, 6, 6, 6, 6, 6, 7];
+
+// The base value and extra bit probabilities of DCT_CAT1 to DCT_CAT6.
+const CATEGORIES: [(u32, &[u8]); 6] = [
+    (5, &[159]),
+    (7, &[165, 145]),
+    (11, &[173, 148, 140]),
+    (19, &[176, 155, 140, 135]),
+    (35, &[180, 157, 141, 134, 130]),
+    (67, &[254, 254, 243, 230, 196, 177, 153, 140, 133, 130, 129]),
+];
+
+// [block type][band][context][branch]: luma after Y2, Y2, chroma, luma with DC.
+type TokenProbs = [[[[u8; 11]; 3]; 8]; 4];
+
+// The chance of each default probability being replaced, section 13.4 of RFC 6386.
+#[rustfmt::skip]
+const COEFF_UPDATE_PROBS: TokenProbs = [
+    [
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[176, 246, 255, 255, 255, 255, 255, 255, 255, 255, 255], [223, 241, 252, 255, 255, 255, 255, 255, 255, 255, 255], [249, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 244, 252, 255, 255, 255, 255, 255, 255, 255, 255], [234, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 246, 254, 255, 255, 255, 255, 255, 255, 255, 255], [239, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255], [251, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [251, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 253, 255, 254, 255, 255, 255, 255, 255, 255], [250, 255, 254, 255, 254, 255, 255, 255, 255, 255, 255], [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+    ],
+    [
+        [[217, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [225, 252, 241, 253, 255, 255, 254, 255, 255, 255, 255], [234, 250, 241, 250, 253, 255, 253, 254, 255, 255, 255]],
+        [[255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255], [223, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [238, 253, 254, 254, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 248, 254, 255, 255, 255, 255, 255, 255, 255, 255], [249, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 253, 255, 255, 255, 255, 255, 255, 255, 255, 255], [247, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [252, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [253, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255], [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+    ],
+    [
+        [[186, 251, 250, 255, 255, 255, 255, 255, 255, 255, 255], [234, 251, 244, 254, 255, 255, 255, 255, 255, 255, 255], [251, 251, 243, 253, 254, 255, 254, 255, 255, 255, 255]],
+        [[255, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [236, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [251, 253, 253, 254, 254, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [254, 254, 254, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255], [254, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+    ],
+    [
+        [[248, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [250, 254, 252, 254, 255, 255, 255, 255, 255, 255, 255], [248, 254, 249, 253, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255], [246, 253, 253, 255, 255, 255, 255, 255, 255, 255, 255], [252, 254, 251, 254, 254, 255, 255, 255, 255, 255, 255]],
+        [[255, 254, 252, 255, 255, 255, 255, 255, 255, 255, 255], [248, 254, 253, 255, 255, 255, 255, 255, 255, 255, 255], [253, 255, 254, 254, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255], [245, 251, 254, 255, 255, 255, 255, 255, 255, 255, 255], [253, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 251, 253, 255, 255, 255, 255, 255, 255, 255, 255], [252, 253, 254, 255, 255, 255, 255, 255, 255, 255, 255], [255, 254, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 252, 255, 255, 255, 255, 255, 255, 255, 255, 255], [249, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 254, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 253, 255, 255, 255, 255, 255, 255, 255, 255], [250, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+        [[255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [254, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255], [255, 255, 255, 255, 255, 255, 255, 255, 255, 255, 255]],
+    ],
+];
+
+// Section 13.5 of RFC 6386, the defaults we never update.
+#[rustfmt::skip]
+const COEFF_PROBS: TokenProbs = [
+    [
+        [[128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128], [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128], [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128]],
+        [[253, 136, 254, 255, 228, 219, 128, 128, 128, 128, 128], [189, 129, 242, 255, 227, 213, 255, 219, 128, 128, 128], [106, 126, 227, 252, 214, 209, 255, 255, 128, 128, 128]],
+        [[  1,  98, 248, 255, 236, 226, 255, 255, 128, 128, 128], [181, 133, 238, 254, 221, 234, 255, 154, 128, 128, 128], [ 78, 134, 202, 247, 198, 180, 255, 219, 128, 128, 128]],
+        [[  1, 185, 249, 255, 243, 255, 128, 128, 128, 128, 128], [184, 150, 247, 255, 236, 224, 128, 128, 128, 128, 128], [ 77, 110, 216, 255, 236, 230, 128, 128, 128, 128, 128]],
+        [[  1, 101, 251, 255, 241, 255, 128, 128, 128, 128, 128], [170, 139, 241, 252, 236, 209, 255, 255, 128, 128, 128], [ 37, 116, 196, 243, 228, 255, 255, 255, 128, 128, 128]],
+        [[  1, 204, 254, 255, 245, 255, 128, 128, 128, 128, 128], [207, 160, 250, 255, 238, 128, 128, 128, 128, 128, 128], [102, 103, 231, 255, 211, 171, 128, 128, 128, 128, 128]],
+        [[  1, 152, 252, 255, 240, 255, 128, 128, 128, 128, 128], [177, 135, 243, 255, 234, 225, 128, 128, 128, 128, 128], [ 80, 129, 211, 255, 194, 224, 128, 128, 128, 128, 128]],
+        [[  1,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128], [246,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128], [255, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128]],
+    ],
+    [
+        [[198,  35, 237, 223, 193, 187, 162, 160, 145, 155,  62], [131,  45, 198, 221, 172, 176, 220, 157, 252, 221,   1], [ 68,  47, 146, 208, 149, 167, 221, 162, 255, 223, 128]],
+        [[  1, 149, 241, 255, 221, 224, 255, 255, 128, 128, 128], [184, 141, 234, 253, 222, 220, 255, 199, 128, 128, 128], [ 81,  99, 181, 242, 176, 190, 249, 202, 255, 255, 128]],
+        [[  1, 129, 232, 253, 214, 197, 242, 196, 255, 255, 128], [ 99, 121, 210, 250, 201, 198, 255, 202, 128, 128, 128], [ 23,  91, 163, 242, 170, 187, 247, 210, 255, 255, 128]],
+        [[  1, 200, 246, 255, 234, 255, 128, 128, 128, 128, 128], [109, 178, 241, 255, 231, 245, 255, 255, 128, 128, 128], [ 44, 130, 201, 253, 205, 192, 255, 255, 128, 128, 128]],
+        [[  1, 132, 239, 251, 219, 209, 255, 165, 128, 128, 128], [ 94, 136, 225, 251, 218, 190, 255, 255, 128, 128, 128], [ 22, 100, 174, 245, 186, 161, 255, 199, 128, 128, 128]],
+        [[  1, 182, 249, 255, 232, 235, 128, 128, 128, 128, 128], [124, 143, 241, 255, 227, 234, 128, 128, 128, 128, 128], [ 35,  77, 181, 251, 193, 211, 255, 205, 128, 128, 128]],
+        [[  1, 157, 247, 255, 236, 231, 255, 255, 128, 128, 128], [121, 141, 235, 255, 225, 227, 255, 255, 128, 128, 128], [ 45,  99, 188, 251, 195, 217, 255, 224, 128, 128, 128]],
+        [[  1,   1, 251, 255, 213, 255, 128, 128, 128, 128, 128], [203,   1, 248, 255, 255, 128, 128, 128, 128, 128, 128], [137,   1, 177, 255, 224, 255, 128, 128, 128, 128, 128]],
+    ],
+    [
+        [[253,   9, 248, 251, 207, 208, 255, 192, 128, 128, 128], [175,  13, 224, 243, 193, 185, 249, 198, 255, 255, 128], [ 73,  17, 171, 221, 161, 179, 236, 167, 255, 234, 128]],
+        [[  1,  95, 247, 253, 212, 183, 255, 255, 128, 128, 128], [239,  90, 244, 250, 211, 209, 255, 255, 128, 128, 128], [155,  77, 195, 248, 188, 195, 255, 255, 128, 128, 128]],
+        [[  1,  24, 239, 251, 218, 219, 255, 205, 128, 128, 128], [201,  51, 219, 255, 196, 186, 128, 128, 128, 128, 128], [ 69,  46, 190, 239, 201, 218, 255, 228, 128, 128, 128]],
+        [[  1, 191, 251, 255, 255, 128, 128, 128, 128, 128, 128], [223, 165, 249, 255, 213, 255, 128, 128, 128, 128, 128], [141, 124, 248, 255, 255, 128, 128, 128, 128, 128, 128]],
+        [[  1,  16, 248, 255, 255, 128, 128, 128, 128, 128, 128], [190,  36, 230, 255, 236, 255, 128, 128, 128, 128, 128], [149,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128]],
+        [[  1, 226, 255, 128, 128, 128, 128, 128, 128, 128, 128], [247, 192, 255, 128, 128, 128, 128, 128, 128, 128, 128], [240, 128, 255, 128, 128, 128, 128, 128, 128, 128, 128]],
+        [[  1, 134, 252, 255, 255, 128, 128, 128, 128, 128, 128], [213,  62, 250, 255, 255, 128, 128, 128, 128, 128, 128], [ 55,  93, 255, 128, 128, 128, 128, 128, 128, 128, 128]],
+        [[128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128], [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128], [128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128]],
+    ],
+    [
+        [[202,  24, 213, 235, 186, 191, 220, 160, 240, 175, 255], [126,  38, 182, 232, 169, 184, 228, 174, 255, 187, 128], [ 61,  46, 138, 219, 151, 178, 240, 170, 255, 216, 128]],
+        [[  1, 112, 230, 250, 199, 191, 247, 159, 255, 255, 128], [166, 109, 228, 252, 211, 215, 255, 174, 128, 128, 128], [ 39,  77, 162, 232, 172, 180, 245, 178, 255, 255, 128]],
+        [[  1,  52, 220, 246, 198, 199, 249, 220, 255, 255, 128], [124,  74, 191, 243, 183, 193, 250, 221, 255, 255, 128], [ 24,  71, 130, 219, 154, 170, 243, 182, 255, 255, 128]],
+        [[  1, 182, 225, 249, 219, 240, 255, 224, 128, 128, 128], [149, 150, 226, 252, 216, 205, 255, 171, 128, 128, 128], [ 28, 108, 170, 242, 183, 194, 254, 223, 255, 255, 128]],
+        [[  1,  81, 230, 252, 204, 203, 255, 192, 128, 128, 128], [123, 102, 209, 247, 188, 196, 255, 233, 128, 128, 128], [ 20,  95, 153, 243, 164, 173, 255, 203, 128, 128, 128]],
+        [[  1, 222, 248, 255, 216, 213, 128, 128, 128, 128, 128], [168, 175, 246, 252, 235, 205, 255, 255, 128, 128, 128], [ 47, 116, 215, 255, 211, 212, 255, 255, 128, 128, 128]],
+        [[  1, 121, 236, 253, 212, 214, 255, 255, 128, 128, 128], [141,  84, 213, 252, 201, 202, 255, 219, 128, 128, 128], [ 42,  80, 160, 240, 162, 185, 255, 205, 128, 128, 128]],
+        [[  1,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128], [244,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128], [238,   1, 255, 128, 128, 128, 128, 128, 128, 128, 128]],
+    ],
+];
+
+// Section 14.1 of RFC 6386.
+#[rustfmt::skip]
+const DC_QUANT: [i32; 128] = [
+      4,   5,   6,   7,   8,   9,  10,  10,  11,  12,  13,  14,  15,  16,  17,  17,
+     18,  19,  20,  20,  21,  21,  22,  22,  23,  23,  24,  25,  25,  26,  27,  28,
+     29,  30,  31,  32,  33,  34,  35,  36,  37,  37,  38,  39,  40,  41,  42,  43,
+     44,  45,  46,  46,  47,  48,  49,  50,  51,  52,  53,  54,  55,  56,  57,  58,
+     59,  60,  61,  62,  63,  64,  65,  66,  67,  68,  69,  70,  71,  72,  73,  74,
+     75,  76,  76,  77,  78,  79,  80,  81,  82,  83,  84,  85,  86,  87,  88,  89,
+     91,  93,  95,  96,  98, 100, 101, 102, 104, 106, 108, 110, 112, 114, 116, 118,
+    122, 124, 126, 128, 130, 132, 134, 136, 138, 140, 143, 145, 148, 151, 154, 157,
+];
+
+#[rustfmt::skip]
+const AC_QUANT: [i32; 128] = [
+      4,   5,   6,   7,   8,   9,  10,  11,  12,  13,  14,  15,  16,  17,  18,  19,
+     20,  21,  22,  23,  24,  25,  26,  27,  28,  29,  30,  31,  32,  33,  34,  35,
+     36,  37,  38,  39,  40,  41,  42,  43,  44,  45,  46,  47,  48,  49,  50,  51,
+     52,  53,  54,  55,  56,  57,  58,  60,  62,  64,  66,  68,  70,  72,  74,  76,
+     78,  80,  82,  84,  86,  88,  90,  92,  94,  96,  98, 100, 102, 104, 106, 108,
+    110, 112, 114, 116, 119, 122, 125, 128, 131, 134, 137, 140, 143, 146, 149, 152,
+    155, 158, 161, 164, 167, 170, 173, 177, 181, 185, 189, 193, 197, 201, 205, 209,
+    213, 217, 221, 225, 229, 234, 239, 245, 249, 254, 259, 264, 269, 274, 279, 284,
+];
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgba};
+    use super::*;
+
+    // smooth gradients with a hard edge and some texture, at a size that
+    // doesn't fill whole macroblocks
+    fn sample(width: u32, height: u32, alpha: impl Fn(u32, u32) -> u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(width, height, |x, y| {
+            let edge = if x > width / 2 { 90 } else { 0 };
+            let texture = ((x * 7 + y * 13) % 11) as u8;
+            Rgba([(x * 127 / width) as u8 + texture, (y * 127 / height) as u8 + edge, 200 - edge - texture, alpha(x, y)])
+        }))
+    }
+
+    fn mean_error(a: &DynamicImage, b: &DynamicImage) -> f64 {
+        let (a, b) = (a.to_rgb8(), b.to_rgb8());
+        let total: u64 = a.iter().zip(b.iter()).map(|(&x, &y)| u64::from(x.abs_diff(y))).sum();
+        total as f64 / a.len() as f64
+    }
+
+    fn decode(webp: &[u8]) -> DynamicImage {
+        image::load_from_memory_with_format(webp, image::ImageFormat::WebP).unwrap()
+    }
+
+    #[test]
+    fn opaque_images_are_a_plain_vp8_file() {
+        let img = sample(45, 37, |_, _| 255);
+        let webp = encode_lossy_webp(&img, 90, None).unwrap();
+        assert_eq!(&webp[12..16], b"VP8 ");
+
+        let decoded = decode(&webp);
+        assert_eq!(decoded.dimensions(), (45, 37));
+        assert!(!decoded.color().has_alpha());
+        // most of what is lost is the texture's color, to chroma subsampling
+        let error = mean_error(&img, &decoded);
+        assert!(error < 5.0, "mean error {error}");
+    }
+
+    #[test]
+    fn lower_quality_is_smaller_and_rougher() {
+        let img = sample(64, 64, |_, _| 255);
+        let fine = encode_lossy_webp(&img, 95, None).unwrap();
+        let coarse = encode_lossy_webp(&img, 10, None).unwrap();
+        assert!(coarse.len() < fine.len() / 2, "{} vs {}", coarse.len(), fine.len());
+        assert!(mean_error(&img, &decode(&coarse)) > mean_error(&img, &decode(&fine)));
+    }
+
+    #[test]
+    fn noise_at_full_quality_survives() {
+        // large coefficients need the longest tokens. Gray, so that chroma
+        // subsampling loses nothing.
+        let noise = DynamicImage::ImageRgba8(RgbaImage::from_fn(32, 32, |x, y| {
+            let v = ((x * 73 + y * 151) ^ (x * y * 29)) as u8;
+            Rgba([v, v, v, 255])
+        }));
+        let error = mean_error(&noise, &decode(&encode_lossy_webp(&noise, 100, None).unwrap()));
+        assert!(error < 8.0, "mean error {error}");
+    }
+
+    #[test]
+    fn alpha_is_kept_exactly() {
+        let img = sample(30, 20, |x, y| (x * 8 + y) as u8);
+        let webp = encode_lossy_webp(&img, 75, None).unwrap();
+        assert_eq!(&webp[12..16], b"VP8X");
+
+        let decoded = decode(&webp).to_rgba8();
+        assert!(img.to_rgba8().pixels().zip(decoded.pixels()).all(|(a, b)| a[3] == b[3]));
+    }
+
+    #[test]
+    fn icc_profile_is_embedded() {
+        let icc = b"not really a profile".to_vec();
+        let webp = encode_lossy_webp(&sample(8, 8, |_, _| 255), 75, Some(&icc)).unwrap();
+
+        let mut decoder = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(webp)).unwrap();
+        assert_eq!(image::ImageDecoder::icc_profile(&mut decoder).unwrap(), Some(icc));
+    }
+
+    #[test]
+    fn sides_over_14_bits_are_refused() {
+        let img = DynamicImage::ImageLuma8(GrayImage::new(16384, 1));
+        assert_eq!(
+            encode_lossy_webp(&img, 75, None),
+            Err(ConversionError::DimensionLimitsExceeded { width: 16384, height: 1, max: MAX_SIDE }),
+        );
+    }
+}
@@ -0,0 +1,194 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use futures::channel::oneshot;
+use image::ImageFormat;
+use js_sys::{Array, Uint8Array};
+use wasm_bindgen::prelude::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{MessageEvent, Worker};
+use web_image_converter::error::ConversionError;
+use web_image_converter::options::EncodeOptions;
+use web_image_converter::protocol::{ConversionJob, ConversionResult, JobStage, WorkerReply};
+use web_image_converter::recipe::Recipe;
+
+// trunk's loader shim imports `worker.js` and starts its wasm, the bundle alone never runs
+const WORKER_SCRIPT: &str = "./worker_loader.js";
+
+struct PendingJob {
+    done: oneshot::Sender<ConversionResult>,
+    on_stage: Box<dyn Fn(JobStage)>,
+}
+
+type Pending = Rc<RefCell<HashMap<u64, PendingJob>>>;
+
+struct PoolWorker {
+    worker: Worker,
+    ready: Rc<Cell<bool>>,
+    failed: Rc<Cell<bool>>,
+    backlog: Rc<RefCell<Vec<Uint8Array>>>, // jobs posted before the worker was ready
+    pending: Pending,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(JsValue)>,
+}
+
+impl PoolWorker {
+    fn spawn() -> Result<PoolWorker, JsValue> {
+        let worker = Worker::new(WORKER_SCRIPT)?;
+        let ready = Rc::new(Cell::new(false));
+        let failed = Rc::new(Cell::new(false));
+        let backlog: Rc<RefCell<Vec<Uint8Array>>> = Default::default();
+        let pending: Pending = Default::default();
+
+        let on_message = {
+            let worker = worker.clone();
+            let ready = ready.clone();
+            let backlog = backlog.clone();
+            let pending = pending.clone();
+            Closure::wrap(Box::new(move |msg: MessageEvent| {
+                let data = msg.data();
+                if data.is_instance_of::<Array>() {
+                    ready.set(true);
+                    backlog.take().iter().for_each(|job| post_job(&worker, job));
+                    return;
+                }
+
+                let bytes = Uint8Array::new(&data).to_vec();
+                match WorkerReply::from_bytes(&bytes) {
+                    Ok(WorkerReply::Progress { id, stage }) => {
+                        if let Some(job) = pending.borrow().get(&id) {
+                            (job.on_stage)(stage);
+                        }
+                    },
+                    Ok(WorkerReply::Finished(result)) => {
+                        if let Some(job) = pending.borrow_mut().remove(&result.id) {
+                            let _ = job.done.send(result);
+                        }
+                    },
+                    Err(_) => {},
+                }
+            }) as Box<dyn FnMut(_)>)
+        };
+        worker.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        // a worker that failed to load or panicked won't answer, so drop its jobs
+        let on_error = {
+            let failed = failed.clone();
+            let pending = pending.clone();
+            Closure::wrap(Box::new(move |_: JsValue| {
+                failed.set(true);
+                pending.borrow_mut().clear();
+            }) as Box<dyn FnMut(_)>)
+        };
+        worker.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        Ok(PoolWorker { worker, ready, failed, backlog, pending, _on_message: on_message, _on_error: on_error })
+    }
+
+    fn in_flight(&self) -> usize {
+        self.pending.borrow().len()
+    }
+
+    fn send(&self, id: u64, job: Uint8Array, on_stage: Box<dyn Fn(JobStage)>) -> oneshot::Receiver<ConversionResult> {
+        let (done, receiver) = oneshot::channel();
+        self.pending.borrow_mut().insert(id, PendingJob { done, on_stage });
+        if self.ready.get() {
+            post_job(&self.worker, &job);
+        } else {
+            self.backlog.borrow_mut().push(job);
+        }
+        receiver
+    }
+}
+
+impl Drop for PoolWorker {
+    fn drop(&mut self) {
+        self.worker.terminate();
+    }
+}
+
+fn post_job(worker: &Worker, job: &Uint8Array) {
+    // hand the buffer over instead of copying it, the page doesn't need it anymore
+    let transfer = Array::of1(&job.buffer());
+    worker.post_message_with_transfer(job, &transfer).expect("Failed to post conversion job");
+}
+
+/// Converts images on background workers, one per logical core. A pool
+/// without workers (e.g. when they can't be created) converts on the page
+/// thread instead.
+#[derive(Default)]
+pub struct WorkerPool {
+    workers: RefCell<Vec<PoolWorker>>,
+    next_id: Cell<u64>,
+}
+
+impl WorkerPool {
+    pub fn new() -> WorkerPool {
+        let size = web_sys::window()
+            .map(|window| window.navigator().hardware_concurrency() as usize)
+            .unwrap_or(1)
+            .max(1);
+
+        let workers = (0..size)
+            .map_while(|_| PoolWorker::spawn().ok())
+            .collect();
+
+        WorkerPool { workers: RefCell::new(workers), next_id: Cell::new(0) }
+    }
+
+    /// How many jobs can run at the same time.
+    pub fn capacity(&self) -> usize {
+        self.replace_failed();
+        self.workers.borrow().len().max(1)
+    }
+
+    // a worker that crashed is swapped for a fresh one, one that never got
+    // going is dropped, as another would fail to load the same way
+    fn replace_failed(&self) {
+        let mut workers = self.workers.borrow_mut();
+        for worker in workers.iter_mut().filter(|worker| worker.failed.get() && worker.ready.get()) {
+            if let Ok(fresh) = PoolWorker::spawn() {
+                *worker = fresh;
+            }
+        }
+        workers.retain(|worker| !worker.failed.get());
+    }
+
+    /// Decodes the uploaded `file`, runs `recipe` on it and converts it,
+    /// calling `on_stage` as the job moves from decoding to encoding.
+    pub async fn convert(
+        &self,
+        file: &[u8],
+        auto_orient: bool,
+        recipe: &Recipe,
+        format: ImageFormat,
+        options: EncodeOptions,
+        on_stage: impl Fn(JobStage) + 'static,
+    ) -> Result<Vec<u8>, ConversionError> {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let job = ConversionJob { id, file: file.to_vec(), auto_orient, format, options, recipe: recipe.clone() };
+
+        self.replace_failed();
+        let sent = {
+            let workers = self.workers.borrow();
+            match workers.iter().min_by_key(|worker| worker.in_flight()) {
+                Some(worker) => Ok((worker.ready.clone(), worker.send(id, Uint8Array::from(job.to_bytes().as_slice()), Box::new(on_stage)))),
+                None => Err(on_stage),
+            }
+        };
+        let (ready, reply) = match sent {
+            Ok(sent) => sent,
+            Err(on_stage) => return job.run(on_stage).result, // no worker to take it
+        };
+
+        match reply.await {
+            Ok(response) => response.result,
+            // the worker failed to load, so the job never ran and is safe to run here
+            Err(_) if !ready.get() => job.run(|_| {}).result,
+            // running it again here would take the page down with it
+            Err(_) => Err(ConversionError::WorkerCrashed),
+        }
+    }
+}
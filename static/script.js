@@ -1,5 +1,5 @@
 export  function downloadFile(filename, data) {
-    const blob = new Blob([data], { type: 'application/x-tar' });
+    const blob = new Blob([data], { type: 'application/octet-stream' });
     const url = URL.createObjectURL(blob);
     const a = document.createElement('a');
     a.href = url;
@@ -8,4 +8,63 @@ export  function downloadFile(filename, data) {
     a.click();
     document.body.removeChild(a);
     URL.revokeObjectURL(url);
-}
\ No newline at end of file
+}
+// The session kept between page loads, one IndexedDB store of byte arrays by key.
+const SESSION_DB = 'web-image-converter';
+const SESSION_STORE = 'session';
+let sessionDb = null;
+
+function openSessionDb() {
+    return new Promise((resolve, reject) => {
+        const request = indexedDB.open(SESSION_DB, 1);
+        request.onupgradeneeded = () => request.result.createObjectStore(SESSION_STORE);
+        request.onsuccess = () => resolve(request.result);
+        request.onerror = () => reject(request.error);
+    });
+}
+
+async function inSessionStore(mode, run) {
+    sessionDb ??= openSessionDb();
+    const db = await sessionDb;
+    return new Promise((resolve, reject) => {
+        const transaction = db.transaction(SESSION_STORE, mode);
+        const request = run(transaction.objectStore(SESSION_STORE));
+        transaction.oncomplete = () => resolve(request.result);
+        transaction.onerror = () => reject(transaction.error);
+        transaction.onabort = () => reject(transaction.error);
+    });
+}
+
+export function storeGet(key) {
+    return inSessionStore('readonly', store => store.get(key));
+}
+
+export function storePut(key, data) {
+    return inSessionStore('readwrite', store => store.put(data, key));
+}
+
+export function storeDelete(key) {
+    return inSessionStore('readwrite', store => store.delete(key));
+}
+
+export function storeClear() {
+    return inSessionStore('readwrite', store => store.clear());
+}
+
+// bytes this origin may store, 0 when the browser won't say
+export async function storageQuota() {
+    const estimate = await navigator.storage?.estimate?.().catch(() => null);
+    return estimate?.quota ?? 0;
+}
+
+// Files opened with the installed app, see `file_handlers` in the manifest.
+export function onLaunchFiles(callback) {
+    if (!('launchQueue' in window)) {
+        return;
+    }
+    window.launchQueue.setConsumer(async params => {
+        for (const handle of params.files) {
+            callback(await handle.getFile());
+        }
+    });
+}
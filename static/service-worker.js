@@ -0,0 +1,81 @@
+// Keeps the app working offline. Everything is fetched relative to where the
+// worker lives, so it works under the GitHub Pages repository path too.
+//
+// Files trunk names with a content hash never change and are served from the
+// cache first. The page, the conversion worker and the manifest keep their
+// names across releases, so they come from the network when there is one and
+// from the cache when there isn't.
+
+const CACHE = 'web-image-converter-v1';
+const SHELL = ['./', './worker_loader.js', './worker.js', './worker_bg.wasm', './manifest.webmanifest', './icon-192.png', './icon-512.png', './icon-maskable-512.png'];
+const HASHED = /-[0-9a-f]{16}(_bg)?\.(js|wasm|css|png)$|\/snippets\//;
+const ASSET = /["']([^"'\s]+\.(?:js|wasm|css|png))["']/g;
+
+// the bundle names change with every release, so they are read from the page
+// and from the scripts it loads, which import the wasm-bindgen snippets
+async function assetsIn(url) {
+    const response = await fetch(url);
+    const text = await response.text();
+    return [...text.matchAll(ASSET)].map(([, path]) => new URL(path, response.url).href);
+}
+
+self.addEventListener('install', event => {
+    event.waitUntil((async () => {
+        const page = await assetsIn(self.registration.scope);
+        const imported = await Promise.all(page.filter(url => url.endsWith('.js')).map(assetsIn));
+        const urls = new Set([...SHELL.map(path => new URL(path, self.registration.scope).href), ...page, ...imported.flat()]);
+
+        // one missing file shouldn't keep the rest from working offline
+        const cache = await caches.open(CACHE);
+        await Promise.all([...urls].map(url => cache.add(url).catch(() => {})));
+        await self.skipWaiting();
+    })());
+});
+
+self.addEventListener('activate', event => {
+    event.waitUntil((async () => {
+        const names = await caches.keys();
+        await Promise.all(names.filter(name => name !== CACHE).map(name => caches.delete(name)));
+        await self.clients.claim();
+    })());
+});
+
+async function fromCacheFirst(request) {
+    const cached = await caches.match(request);
+    if (cached) {
+        return cached;
+    }
+    const response = await fetch(request);
+    if (response.ok) {
+        const cache = await caches.open(CACHE);
+        await cache.put(request, response.clone());
+    }
+    return response;
+}
+
+async function fromNetworkFirst(request) {
+    try {
+        const response = await fetch(request);
+        if (response.ok) {
+            const cache = await caches.open(CACHE);
+            await cache.put(request.mode === 'navigate' ? './' : request, response.clone());
+        }
+        return response;
+    } catch (err) {
+        // files opened from the OS arrive as a navigation to the start URL, possibly with a query
+        const cached = await caches.match(request.mode === 'navigate' ? './' : request);
+        if (cached) {
+            return cached;
+        }
+        throw err;
+    }
+}
+
+self.addEventListener('fetch', event => {
+    const request = event.request;
+    if (request.method !== 'GET' || new URL(request.url).origin !== self.location.origin) {
+        return;
+    }
+    const hashed = HASHED.test(new URL(request.url).pathname);
+    event.respondWith(hashed ? fromCacheFirst(request) : fromNetworkFirst(request));
+});